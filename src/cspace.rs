@@ -0,0 +1,140 @@
+use mork_capability::cap::{CNodeCap, Cap, CapType};
+use mork_capability::cnode::CapNode;
+use mork_common::constants::MAX_CNODE_SIZE;
+use mork_common::mork_kernel_log;
+use mork_common::syscall::message_info::{MessageInfo, ResponseLabel};
+use mork_hal::context::HALContextTrait;
+
+/// Index bits consumed by one CNode level.
+pub const CNODE_RADIX_BITS: usize = MAX_CNODE_SIZE.trailing_zeros() as usize;
+pub const WORD_BITS: usize = usize::BITS as usize;
+/// Bits of CNodeMutate data holding the guard size; the guard sits above them.
+pub const CNODE_GUARD_SIZE_BITS: usize = 6;
+/// Cap arguments carry their lookup depth in the top `CPTR_DEPTH_BITS`, above
+/// `CPTR_BITS` of cap pointer. A depth of zero resolves the full word.
+pub const CPTR_DEPTH_BITS: usize = 8;
+pub const CPTR_BITS: usize = WORD_BITS - CPTR_DEPTH_BITS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupFault {
+    InvalidRoot,
+    MissingCapability { bits_left: usize },
+    DepthMismatch { bits_left: usize, bits_found: usize },
+    GuardMismatch { bits_left: usize, guard_found: usize, guard_size: usize },
+}
+
+impl LookupFault {
+    /// Writes the fault kind to MR0 and its details to MR1..MR3, and returns
+    /// the `FailedLookup` response for the tag.
+    pub fn report(&self, hal_context: &mut impl HALContextTrait) -> MessageInfo {
+        let (kind, details) = match *self {
+            LookupFault::InvalidRoot => (0, [0, 0, 0]),
+            LookupFault::MissingCapability { bits_left } => (1, [bits_left, 0, 0]),
+            LookupFault::DepthMismatch { bits_left, bits_found } => (2, [bits_left, bits_found, 0]),
+            LookupFault::GuardMismatch { bits_left, guard_found, guard_size } => {
+                (3, [bits_left, guard_found, guard_size])
+            }
+        };
+        hal_context.set_mr(0, kind);
+        for (i, detail) in details.into_iter().enumerate() {
+            hal_context.set_mr(i + 1, detail);
+        }
+        MessageInfo::new_response(ResponseLabel::FailedLookup)
+    }
+}
+
+pub fn cnode_from_cap(cap: &CNodeCap) -> &'static mut CapNode {
+    let ptr = (cap.base_ptr() << 12) as usize;
+    unsafe { &mut *(ptr as *mut CapNode) }
+}
+
+/// Walks `cptr` from the least significant bit upwards. Each level consumes
+/// `CNODE_RADIX_BITS` of slot index, then the guard of the CNode cap found in
+/// that slot. The walk ends after `depth` bits, or at the first slot that does
+/// not hold a CNode cap, in which case the remaining bits must be zero.
+/// Like the objects behind `from_cap`, the CapNode found lives as long as the
+/// cap to it, not as long as the borrow of `root`.
+pub fn resolve_address_bits(root: &CapNode, cptr: usize, depth: usize)
+    -> Result<(&'static mut CapNode, usize), LookupFault> {
+    let depth = depth.min(WORD_BITS);
+    if depth < WORD_BITS && cptr >> depth != 0 {
+        return Err(LookupFault::DepthMismatch {
+            bits_left: depth,
+            bits_found: WORD_BITS - cptr.leading_zeros() as usize,
+        });
+    }
+    let mut node = unsafe { &mut *(root as *const CapNode as *mut CapNode) };
+    let mut addr = cptr;
+    let mut bits_left = depth;
+    loop {
+        if bits_left < CNODE_RADIX_BITS {
+            return Err(LookupFault::DepthMismatch { bits_left, bits_found: CNODE_RADIX_BITS });
+        }
+        let index = addr & (MAX_CNODE_SIZE - 1);
+        addr >>= CNODE_RADIX_BITS;
+        bits_left -= CNODE_RADIX_BITS;
+        let cap = node[index];
+        if bits_left == 0 || cap.get_type() != CapType::CNode {
+            return if addr == 0 {
+                Ok((node, index))
+            } else {
+                Err(LookupFault::MissingCapability { bits_left })
+            };
+        }
+        let cnode_cap = unsafe { cap.cnode_cap };
+        let guard_size = cnode_cap.guard_size() as usize;
+        if guard_size > bits_left {
+            return Err(LookupFault::DepthMismatch { bits_left, bits_found: guard_size });
+        }
+        let guard_found = addr & ((1 << guard_size) - 1);
+        if guard_found != cnode_cap.guard() as usize {
+            return Err(LookupFault::GuardMismatch { bits_left, guard_found, guard_size });
+        }
+        addr >>= guard_size;
+        bits_left -= guard_size;
+        if bits_left == 0 {
+            return Ok((node, index));
+        }
+        node = cnode_from_cap(&cnode_cap);
+    }
+}
+
+/// Resolves `cptr` to a non-empty slot and returns a copy of its cap.
+pub fn lookup_cap(root: &mut CapNode, cptr: usize) -> Result<Cap, LookupFault> {
+    let (node, index) = resolve_address_bits(root, cptr, WORD_BITS)?;
+    if node.is_used(index) {
+        Ok(node[index])
+    } else {
+        Err(LookupFault::MissingCapability { bits_left: 0 })
+    }
+}
+
+/// Splits a cap argument into its cap pointer and lookup depth. A depth that
+/// ends on a slot holding a CNode cap names that cap instead of a slot in
+/// the CNode it points to.
+pub fn decode_cap_arg(arg: usize) -> (usize, usize) {
+    let cptr = arg & ((1 << CPTR_BITS) - 1);
+    match arg >> CPTR_BITS {
+        0 => (cptr, WORD_BITS),
+        depth => (cptr, depth),
+    }
+}
+
+/// Resolves a cap argument encoded as by `decode_cap_arg`.
+pub fn resolve_cap_arg(root: &CapNode, arg: usize) -> Result<(&'static mut CapNode, usize), LookupFault> {
+    let (cptr, depth) = decode_cap_arg(arg);
+    resolve_address_bits(root, cptr, depth)
+}
+
+/// Resolves the cap argument passed in message register `mr`. A failed lookup
+/// is answered with `OutOfRange`, with the fault details in the message
+/// registers as for `FailedLookup`.
+pub fn lookup_mr_slot(root: &mut CapNode, hal_context: &mut impl HALContextTrait, mr: usize)
+    -> Result<(&'static mut CapNode, usize), MessageInfo> {
+    let cptr = hal_context.get_mr(mr);
+    resolve_cap_arg(root, cptr).map_err(|fault| {
+        mork_kernel_log!(warn, "lookup cap {:#x} failed: {:?}", cptr, fault);
+        fault.report(hal_context);
+        MessageInfo::new_response(ResponseLabel::OutOfRange)
+    })
+}
\ No newline at end of file
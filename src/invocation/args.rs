@@ -0,0 +1,58 @@
+use mork_capability::cap::{Cap, CapType};
+use mork_capability::cnode::CapNode;
+use mork_common::mork_kernel_log;
+use mork_common::syscall::message_info::{MessageInfo, ResponseLabel};
+use mork_hal::context::HALContextTrait;
+use crate::cspace::lookup_mr_slot;
+use crate::mdb;
+
+/// A cap argument decoded from a message register, with the slot it lives in
+/// so handlers can write an updated cap back.
+pub struct CapArg<'a> {
+    pub node: &'a mut CapNode,
+    pub slot: usize,
+    pub cap: Cap,
+}
+
+impl CapArg<'_> {
+    pub fn update(&mut self, cap: Cap) {
+        mdb::update_slot(self.node, self.slot, cap);
+    }
+}
+
+/// Decodes message register `mr` to a non-empty slot of any cap type. Slots
+/// that do not resolve are rejected with `OutOfRange`, empty ones with
+/// `ErrCapType`.
+pub fn any_cap_arg(cspace: &mut CapNode, hal_context: &mut impl HALContextTrait, mr: usize)
+    -> Result<CapArg<'static>, MessageInfo> {
+    let (node, slot) = lookup_mr_slot(cspace, hal_context, mr)?;
+    if !node.is_used(slot) {
+        mork_kernel_log!(warn, "cap argument {} is empty", mr);
+        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
+    }
+    let cap = node[slot];
+    Ok(CapArg { node, slot, cap })
+}
+
+/// Decodes message register `mr` to a slot holding a cap of `cap_type`.
+pub fn cap_arg(cspace: &mut CapNode, hal_context: &mut impl HALContextTrait, mr: usize, cap_type: CapType)
+    -> Result<CapArg<'static>, MessageInfo> {
+    let arg = any_cap_arg(cspace, hal_context, mr)?;
+    if arg.cap.get_type() != cap_type {
+        mork_kernel_log!(warn, "cap argument {}: except {:?}, found: {:?}", mr, cap_type, arg.cap.get_type());
+        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
+    }
+    Ok(arg)
+}
+
+/// Decodes message register `mr` to an empty slot. Slots that do not resolve
+/// are rejected with `OutOfRange`, used ones with `InvalidParam`.
+pub fn empty_slot_arg(cspace: &mut CapNode, hal_context: &mut impl HALContextTrait, mr: usize)
+    -> Result<(&'static mut CapNode, usize), MessageInfo> {
+    let (node, slot) = lookup_mr_slot(cspace, hal_context, mr)?;
+    if node.is_used(slot) {
+        mork_kernel_log!(warn, "slot argument {} is used", mr);
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
+    Ok((node, slot))
+}
\ No newline at end of file
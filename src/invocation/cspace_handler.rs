@@ -1,16 +1,30 @@
 use alloc::alloc::{alloc_zeroed, dealloc};
+use alloc::vec::Vec;
 use core::alloc::Layout;
-use mork_capability::cap::{CNodeCap, CapType, FrameCap, NotificationCap, PageTableCap, ThreadCap};
+use mork_capability::cap::{CNodeCap, Cap, CapType, EndpointCap, FrameCap, NotificationCap, PageTableCap, ReplyCap, ThreadCap, UntypedCap};
 use mork_capability::cnode::CapNode;
 use mork_capability::free_callback::CallbackHandler;
-use mork_common::constants::{CNodeSlot, ObjectType, MAX_CNODE_SIZE, MAX_THREAD_PIRO};
+use mork_common::constants::{ObjectType, MAX_CNODE_SIZE, MAX_THREAD_PIRO};
 use mork_common::mork_kernel_log;
 use mork_common::syscall::message_info::{InvocationLabel, MessageInfo, ResponseLabel};
-use mork_hal::config::{PAGE_SIZE_2M, PAGE_SIZE_NORMAL};
+use mork_common::types::CapRights;
+use mork_hal::config::{PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_NORMAL};
 use mork_hal::context::HALContextTrait;
 use mork_mm::page_table::PageTable;
 use mork_task::task::TaskContext;
+use mork_task::task_state::ThreadStateEnum;
 use mork_ipc::notification::Notification;
+use crate::cspace::{cnode_from_cap, lookup_mr_slot, CNODE_GUARD_SIZE_BITS, CNODE_RADIX_BITS, CPTR_BITS};
+use crate::invocation::args::{any_cap_arg, cap_arg, empty_slot_arg};
+use crate::invocation::memory_handler::forget_vspace;
+use crate::invocation::untyped_handler::{self, free_memory};
+use crate::ipc;
+use crate::ipc::endpoint::{cancel_ipc, Endpoint};
+use crate::ipc::notification::cancel_wait;
+use crate::ipc::reply::{cancel_reply, caller_slot, forget_reply};
+use crate::mdb;
+use crate::quota;
+use crate::reaper::defer_free_task;
 
 pub fn handle(current: &mut TaskContext, dest_cap: ThreadCap, message_info: MessageInfo) -> Result<usize, MessageInfo> {
     let task = TaskContext::from_cap(&dest_cap);
@@ -22,44 +36,107 @@ pub fn handle(current: &mut TaskContext, dest_cap: ThreadCap, message_info: Mess
     let cspace = task.cspace.as_mut().unwrap();
     match InvocationLabel::from_usize(message_info.get_label()) {
         InvocationLabel::CNodeAlloc => {
-            let mut handler = AllocHandler { cspace };
+            if task.get_ptr() != current.get_ptr() {
+                mork_kernel_log!(warn, "CNodeAlloc only allocates into the caller's cspace, use CNodeAllocChild");
+                return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+            }
             let obj_type = ObjectType::from_usize(current.hal_context.get_mr(0));
-            handler.handle(obj_type)
+            let size = get_layout(&obj_type).map_or(0, |(size, _)| size);
+            let count = current.hal_context.get_mr(1).max(1);
+            let dest_cptr = current.hal_context.get_mr(2);
+            let dest = match dest_cptr {
+                0 => None,
+                _ => Some(empty_slot_arg(cspace, &mut current.hal_context, 2)?),
+            };
+            let mut handler = AllocHandler { cspace };
+            let (node, first) = handler.handle(obj_type, count, dest)?;
+            let cap = node[first];
+            let first = if dest_cptr != 0 { dest_cptr } else { first };
+            set_object_metadata(current, &cap, size, first);
+            current.hal_context.set_mr(1, count);
+            Ok(first)
+        }
+        InvocationLabel::CNodeAllocChild => {
+            alloc_child(current, task, ObjectType::from_usize(current.hal_context.get_mr(0)))
         }
 
         InvocationLabel::CNodeDelete => {
-            let object_idx = current.hal_context.get_mr(0);
-            cspace.free_slot(object_idx);
-            Ok(object_idx)
+            let target = any_cap_arg(cspace, &mut current.hal_context, 0)?;
+            mdb::delete_slot(target.node, target.slot);
+            Ok(current.hal_context.get_mr(0))
+        }
+        InvocationLabel::CNodeRevoke => {
+            let target = any_cap_arg(cspace, &mut current.hal_context, 0)?;
+            mdb::revoke(target.node, target.slot);
+            untyped_handler::reset(target.node, target.slot);
+            Ok(current.hal_context.get_mr(0))
         }
         InvocationLabel::CNodeCopy => {
-            let src_cap = cspace[current.hal_context.get_mr(0)];
-            let input_cap = cspace[current.hal_context.get_mr(1)];
-            let dest_slot = current.hal_context.get_mr(2);
-            if input_cap.get_type() != CapType::Thread {
-                mork_kernel_log!(warn, "except thread cap, found: {:?}", input_cap.get_type());
+            let src = any_cap_arg(cspace, &mut current.hal_context, 0)?;
+            let src_cap = src.cap;
+            let input_cap = cap_arg(cspace, &mut current.hal_context, 1, CapType::Thread)?.cap;
+            if matches!(src_cap.get_type(), CapType::Reply | CapType::Untyped) {
+                mork_kernel_log!(warn, "{:?} cap can not be copied", src_cap.get_type());
                 return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
             }
             let dest_task_cap = unsafe { input_cap.thread_cap };
             let dest_task = TaskContext::from_cap(&dest_task_cap);
             if let Some(dest_cspace) = dest_task.cspace.as_mut() {
-                if dest_cspace.is_used(dest_slot) {
-                    if let Some(slot) = dest_cspace.alloc_free() {
-                        dest_cspace[slot] = src_cap.derive();
-                        Ok(slot)
-                    } else {
-                        mork_kernel_log!(warn, "dest cspace not found");
-                        Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace))
-                    }
+                let dest_cptr = current.hal_context.get_mr(2);
+                let (dest_node, dest_slot) = lookup_mr_slot(dest_cspace, &mut current.hal_context, 2)?;
+                if !dest_node.is_used(dest_slot) {
+                    mdb::insert_derived(src.node, src.slot, dest_node, dest_slot, src_cap.derive());
+                    Ok(dest_cptr)
+                } else if let Some(slot) = dest_cspace.alloc_free() {
+                    mdb::insert_derived(src.node, src.slot, dest_cspace, slot, src_cap.derive());
+                    Ok(slot)
                 } else {
-                    dest_cspace[dest_slot] = src_cap.derive();
-                    Ok(dest_slot)
+                    mork_kernel_log!(warn, "dest cspace not found");
+                    Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace))
                 }
             } else {
                 mork_kernel_log!(warn, "dest cspace not found");
                 Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace))
             }
         }
+        InvocationLabel::CNodeMint => {
+            let src = any_cap_arg(cspace, &mut current.hal_context, 0)?;
+            let rights_arg = current.hal_context.get_mr(2);
+            let badge = current.hal_context.get_mr(3);
+            let Some(rights) = u8::try_from(rights_arg).ok().and_then(CapRights::from_bits) else {
+                mork_kernel_log!(warn, "Invalid cap rights: {}", rights_arg);
+                return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+            };
+            let minted_cap = mint_cap(src.cap, rights, badge)?;
+            let (node, dest_slot) = empty_slot_arg(cspace, &mut current.hal_context, 1)?;
+            mdb::insert_derived(src.node, src.slot, node, dest_slot, minted_cap);
+            Ok(current.hal_context.get_mr(1))
+        }
+        InvocationLabel::CNodeMove => {
+            let (dest_node, dest_slot) = dest_task_slot(cspace, &mut current.hal_context)?;
+            let src = any_cap_arg(cspace, &mut current.hal_context, 0)?;
+            mdb::move_slot(src.node, src.slot, dest_node, dest_slot);
+            Ok(current.hal_context.get_mr(2))
+        }
+        InvocationLabel::CNodeMutate => {
+            let data = current.hal_context.get_mr(3);
+            let (dest_node, dest_slot) = dest_task_slot(cspace, &mut current.hal_context)?;
+            let src = any_cap_arg(cspace, &mut current.hal_context, 0)?;
+            let mutated_cap = mutate_cap(src.cap, data)?;
+            mdb::move_slot(src.node, src.slot, dest_node, dest_slot);
+            mdb::update_slot(dest_node, dest_slot, mutated_cap);
+            Ok(current.hal_context.get_mr(2))
+        }
+        InvocationLabel::CNodeSaveCaller => {
+            let (node, dest_slot) = empty_slot_arg(cspace, &mut current.hal_context, 0)?;
+            if let Some((reply_node, reply_slot)) = caller_slot(current) {
+                mdb::move_slot(reply_node, reply_slot, node, dest_slot);
+                Ok(current.hal_context.get_mr(0))
+            } else {
+                mork_kernel_log!(warn, "there is no caller to save");
+                Err(MessageInfo::new_response(ResponseLabel::InvalidParam))
+            }
+        }
         _ => {
             mork_kernel_log!(warn, "unSupported invocation label: {}", message_info.get_label());
             Err(MessageInfo::new_response(ResponseLabel::UnSupported))
@@ -67,74 +144,353 @@ pub fn handle(current: &mut TaskContext, dest_cap: ThreadCap, message_info: Mess
     }
 }
 
+/// Resolves the empty slot named by MR2 in the cspace of the thread cap in MR1.
+fn dest_task_slot(cspace: &mut CapNode, hal_context: &mut impl HALContextTrait)
+    -> Result<(&'static mut CapNode, usize), MessageInfo> {
+    let input_cap = cap_arg(cspace, hal_context, 1, CapType::Thread)?.cap;
+    let dest_task_cap = unsafe { input_cap.thread_cap };
+    let dest_task = TaskContext::from_cap(&dest_task_cap);
+    if let Some(dest_cspace) = dest_task.cspace.as_mut() {
+        empty_slot_arg(dest_cspace, hal_context, 2)
+    } else {
+        mork_kernel_log!(warn, "dest cspace not found");
+        Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace))
+    }
+}
+
+fn mint_cap(src_cap: Cap, rights: CapRights, badge: usize) -> Result<Cap, MessageInfo> {
+    if src_cap.get_type() == CapType::Untyped {
+        mork_kernel_log!(warn, "untyped cap can not be minted");
+        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
+    }
+    update_badge_and_rights(src_cap.derive(), rights, badge)
+}
+
+/// Moving a cap may set a badge on notification and endpoint caps, or the
+/// guard on CNode caps, encoded as `guard << CNODE_GUARD_SIZE_BITS | guard_size`.
+/// A CNode cap is named by a source cap argument whose depth ends on its slot.
+fn mutate_cap(cap: Cap, data: usize) -> Result<Cap, MessageInfo> {
+    match cap.get_type() {
+        CapType::CNode => {
+            let mut cnode_cap = unsafe { cap.cnode_cap };
+            let guard_size = data & ((1 << CNODE_GUARD_SIZE_BITS) - 1);
+            let guard = data >> CNODE_GUARD_SIZE_BITS;
+            if guard_size > CPTR_BITS - CNODE_RADIX_BITS || guard >> guard_size != 0 {
+                mork_kernel_log!(warn, "Invalid guard: {:#x}, size: {}", guard, guard_size);
+                return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+            }
+            cnode_cap.set_guard(guard as u128);
+            cnode_cap.set_guard_size(guard_size as u128);
+            Ok(Cap { cnode_cap })
+        }
+        _ => update_badge_and_rights(cap, CapRights::all(), data),
+    }
+}
+
+/// Sets `badge` and masks the rights of `cap` by `rights`. Only notification
+/// and endpoint caps carry a badge, and a badged cap can not be badged again.
+fn update_badge_and_rights(cap: Cap, rights: CapRights, badge: usize) -> Result<Cap, MessageInfo> {
+    match cap.get_type() {
+        CapType::Notification => {
+            let mut notification_cap = unsafe { cap.notification_cap };
+            if badge != 0 {
+                if notification_cap.badge() != 0 {
+                    mork_kernel_log!(warn, "notification cap is already badged");
+                    return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+                }
+                notification_cap.set_badge(badge as u128);
+            }
+            notification_cap.set_can_send(notification_cap.can_send() & rights.contains(CapRights::W) as u128);
+            notification_cap.set_can_receive(notification_cap.can_receive() & rights.contains(CapRights::R) as u128);
+            Ok(Cap { notification_cap })
+        }
+        CapType::Endpoint => {
+            let mut endpoint_cap = unsafe { cap.endpoint_cap };
+            if badge != 0 {
+                if endpoint_cap.badge() != 0 {
+                    mork_kernel_log!(warn, "endpoint cap is already badged");
+                    return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+                }
+                endpoint_cap.set_badge(badge as u128);
+            }
+            endpoint_cap.set_can_send(endpoint_cap.can_send() & rights.contains(CapRights::W) as u128);
+            endpoint_cap.set_can_receive(endpoint_cap.can_receive() & rights.contains(CapRights::R) as u128);
+            endpoint_cap.set_can_grant(endpoint_cap.can_grant() & rights.contains(CapRights::G) as u128);
+            Ok(Cap { endpoint_cap })
+        }
+        CapType::Reply => {
+            mork_kernel_log!(warn, "reply cap can not be minted or mutated");
+            Err(MessageInfo::new_response(ResponseLabel::ErrCapType))
+        }
+        _ => {
+            if badge != 0 {
+                mork_kernel_log!(warn, "cap type {:?} can not be badged", cap.get_type());
+                return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+            }
+            Ok(cap)
+        }
+    }
+}
+
 struct AllocHandler<'a> {
     pub cspace: &'a mut CapNode
 }
 
 impl AllocHandler<'_> {
-    pub fn handle(&mut self, object_type: ObjectType) -> Result<usize, MessageInfo> {
-        if let Some(slot) = self.cspace.alloc_free() {
-            let (size, align) = Self::get_layout(&object_type);
-            let layout = Layout::from_size_align(size, align).unwrap();
+    /// Allocates `count` objects of `object_type` into consecutive free slots
+    /// and returns the node and first slot they landed in. The slots start at
+    /// `dest` when given, otherwise at the first free run. Either every object
+    /// is created or none is.
+    pub fn handle(&mut self, object_type: ObjectType, count: usize,
+                  dest: Option<(&'static mut CapNode, usize)>)
+        -> Result<(&'static mut CapNode, usize), MessageInfo> {
+        let Some((size, align)) = get_layout(&object_type) else {
+            mork_kernel_log!(warn, "unsupported object type");
+            return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+        };
+        if let ObjectType::Frame1G = object_type {
+            // a 1 GiB frame can never fit the heap quota, retype untyped memory instead
+            mork_kernel_log!(warn, "1 GiB frames can only be retyped from untyped memory");
+            return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+        }
+        if count == 0 || count > MAX_CNODE_SIZE {
+            mork_kernel_log!(warn, "Invalid object count: {}", count);
+            return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+        }
+        if count > 1 && matches!(object_type, ObjectType::Frame4K | ObjectType::Frame2M) {
+            // the object metadata only has room for the physical address of one frame
+            mork_kernel_log!(warn, "frames are allocated one at a time");
+            return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+        }
+        let (dest_node, first) = match dest {
+            Some((node, slot)) => {
+                if !range_is_free(node, slot, count) {
+                    mork_kernel_log!(warn, "dest slots {}..{} are not free", slot, slot + count);
+                    return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+                }
+                (Some(node), slot)
+            }
+            None => {
+                let Some(first) = free_range(self.cspace, count) else {
+                    mork_kernel_log!(warn, "Alloc {} free slots failed", count);
+                    return Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace));
+                };
+                (None, first)
+            }
+        };
+        let cost = size + quota_overhead(&object_type);
+        if !quota::try_charge(self.cspace, cost * count) {
+            mork_kernel_log!(warn, "cspace memory quota exceeded");
+            return Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace));
+        }
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let mut objects = Vec::with_capacity(count);
+        for _ in 0..count {
             let object_ptr = unsafe { alloc_zeroed(layout) };
             if object_ptr.is_null() {
                 mork_kernel_log!(warn, "Alloc memory failed");
-                Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace))
-            } else {
-                match object_type {
-                    ObjectType::Frame4K => {
-                        let cap = FrameCap::new(object_ptr as usize, 3);
-                        self.cspace[slot] = cap.into_cap();
-                        Ok(slot)
-                    }
-                    ObjectType::Frame2M => {
-                        let cap = FrameCap::new(object_ptr as usize, 2);
-                        self.cspace[slot] = cap.into_cap();
-                        Ok(slot)
-                    }
-                    ObjectType::PageTable => {
-                        let cap = PageTableCap::new(object_ptr as usize);
-                        self.cspace[slot] = cap.into_cap();
-                        Ok(slot)
-                    }
-                    ObjectType::Thread => {
-                        let cap = ThreadCap::new(object_ptr as usize);
-                        let task = TaskContext::from_cap(&cap);
-                        *task = TaskContext::new_user_thread();
-                        task.init_cspace();
-                        task.prio = MAX_THREAD_PIRO - 1;
-                        self.cspace[slot] = cap.into_cap();
-                        Ok(slot)
-                    }
-                    ObjectType::Notification => {
-                        let cap = NotificationCap::new(object_ptr as usize);
-                        let notification = Notification::from_cap(&cap);
-                        *notification = Notification::new();
-                        self.cspace[slot] = cap.into_cap();
-                        Ok(slot)
-                    }
-                    _ => {
-                        todo!("not supported")
+                for object_ptr in objects {
+                    unsafe {
+                        dealloc(object_ptr, layout);
                     }
                 }
+                quota::uncharge(self.cspace, cost * count);
+                return Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace));
             }
-        } else {
-            mork_kernel_log!(warn, "Alloc free slot failed");
-            Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace))
+            objects.push(object_ptr);
+        }
+        for object_ptr in objects.iter() {
+            quota::record(self.cspace, *object_ptr as usize, cost);
+        }
+        let node = match dest_node {
+            Some(node) => node,
+            None => unsafe { &mut *(self.cspace as *mut CapNode) },
+        };
+        for (slot, object_ptr) in (first..first + count).zip(objects) {
+            mdb::insert_root(node, slot, init_object(&object_type, object_ptr as usize));
+            adopt_thread(&node[slot], self.cspace);
+        }
+        Ok((node, first))
+    }
+}
+
+/// Finds `count` consecutive free slots, starting the search at the first
+/// free slot so reserved slots are never handed out.
+fn free_range(cspace: &mut CapNode, count: usize) -> Option<usize> {
+    let mut first = cspace.alloc_free()?;
+    let mut slot = first;
+    while slot < MAX_CNODE_SIZE {
+        if cspace.is_used(slot) {
+            first = slot + 1;
+        } else if slot + 1 - first == count {
+            return Some(first);
         }
+        slot += 1;
     }
+    None
+}
 
-    fn get_layout(object_type: &ObjectType) -> (usize, usize) {
-        match object_type {
-            ObjectType::CNode => (size_of::<CapNode>(), PAGE_SIZE_NORMAL),
-            ObjectType::Thread => (size_of::<TaskContext>(), PAGE_SIZE_NORMAL),
-            ObjectType::PageTable => (size_of::<PageTable>(), PAGE_SIZE_NORMAL),
-            ObjectType::Frame4K => (PAGE_SIZE_NORMAL, PAGE_SIZE_NORMAL),
-            ObjectType::Frame2M => (PAGE_SIZE_2M, PAGE_SIZE_2M),
-            ObjectType::Notification => (size_of::<Notification>(), PAGE_SIZE_NORMAL),
-            _ => {
-                panic!("unsupported object type")
+fn range_is_free(node: &CapNode, slot: usize, count: usize) -> bool {
+    slot + count <= MAX_CNODE_SIZE && (slot..slot + count).all(|i| !node.is_used(i))
+}
+
+/// Allocates objects for a child task. The caller keeps the original caps in
+/// its own cspace, at the cptr in MR3 or the first free run, and pays for them
+/// from its quota. The child receives derived copies at the cptr in MR2 or the
+/// first free run, so the caller can revoke them later. Returns the child's
+/// first slot in MR0 and the count in MR1; the caller's first slot comes with
+/// the object metadata.
+fn alloc_child(current: &mut TaskContext, task: &mut TaskContext, object_type: ObjectType)
+    -> Result<usize, MessageInfo> {
+    let child_cspace = task.cspace.as_mut().unwrap();
+    let Some(own_cspace) = current.cspace.as_mut() else {
+        mork_kernel_log!(warn, "caller has no cspace");
+        return Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace));
+    };
+    if core::ptr::eq(&**child_cspace, &**own_cspace) {
+        mork_kernel_log!(warn, "CNodeAllocChild target shares the caller's cspace, use CNodeAlloc");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
+    let size = get_layout(&object_type).map_or(0, |(size, _)| size);
+    let count = current.hal_context.get_mr(1).max(1);
+    let child_cptr = current.hal_context.get_mr(2);
+    let own_cptr = current.hal_context.get_mr(3);
+    let (child_node, child_first) = match child_cptr {
+        0 => {
+            let Some(first) = free_range(child_cspace, count) else {
+                mork_kernel_log!(warn, "Alloc {} free slots in child failed", count);
+                return Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace));
+            };
+            (&mut **child_cspace, first)
+        }
+        _ => {
+            let (node, slot) = empty_slot_arg(child_cspace, &mut current.hal_context, 2)?;
+            if !range_is_free(node, slot, count) {
+                mork_kernel_log!(warn, "child slots {}..{} are not free", slot, slot + count);
+                return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
             }
+            (node, slot)
+        }
+    };
+    let own_dest = match own_cptr {
+        0 => None,
+        _ => Some(empty_slot_arg(own_cspace, &mut current.hal_context, 3)?),
+    };
+    let mut handler = AllocHandler { cspace: own_cspace };
+    let (own_node, own_first) = handler.handle(object_type, count, own_dest)?;
+    for i in 0..count {
+        let cap = own_node[own_first + i].derive();
+        mdb::insert_derived(own_node, own_first + i, child_node, child_first + i, cap);
+    }
+    let cap = own_node[own_first];
+    set_object_metadata(current, &cap, size, if own_cptr != 0 { own_cptr } else { own_first });
+    current.hal_context.set_mr(1, count);
+    Ok(if child_cptr != 0 { child_cptr } else { child_first })
+}
+
+/// Reports a new object in the `alloc_info` words of the caller's IPC buffer,
+/// so the argument registers are left alone: its physical address, only given
+/// for frames, its size, its cap type and the caller's first slot. Frames are
+/// never batched, so for a batch this holds for every object, each in the slot
+/// after the previous one.
+fn set_object_metadata(current: &mut TaskContext, cap: &Cap, size: usize, first: usize) {
+    let Some(buffer) = current.get_ipc_buffer_mut() else {
+        mork_kernel_log!(debug, "no IPC buffer for the object metadata");
+        return;
+    };
+    let paddr = match cap.get_type() {
+        CapType::Frame => mdb::cap_object(cap).unwrap_or(0),
+        _ => 0,
+    };
+    buffer.alloc_info = [paddr, size, cap.get_type() as usize, first];
+}
+
+/// Heap memory an object allocates for itself on creation, charged together
+/// with the object.
+pub fn quota_overhead(object_type: &ObjectType) -> usize {
+    match object_type {
+        ObjectType::Thread => size_of::<CapNode>(),
+        _ => 0,
+    }
+}
+
+/// Makes the cspace of a newly created thread draw from the quota of the
+/// cspace that created it.
+pub fn adopt_thread(cap: &Cap, creator: &CapNode) {
+    if cap.get_type() == CapType::Thread {
+        let task = TaskContext::from_cap(&unsafe { cap.thread_cap });
+        if let Some(cspace) = task.cspace.as_ref() {
+            quota::adopt(cspace, creator);
+        }
+    }
+}
+
+pub fn get_layout(object_type: &ObjectType) -> Option<(usize, usize)> {
+    match object_type {
+        ObjectType::CNode => Some((size_of::<CapNode>(), PAGE_SIZE_NORMAL)),
+        ObjectType::Thread => Some((size_of::<TaskContext>(), PAGE_SIZE_NORMAL)),
+        ObjectType::PageTable => Some((size_of::<PageTable>(), PAGE_SIZE_NORMAL)),
+        ObjectType::Frame4K => Some((PAGE_SIZE_NORMAL, PAGE_SIZE_NORMAL)),
+        ObjectType::Frame2M => Some((PAGE_SIZE_2M, PAGE_SIZE_2M)),
+        ObjectType::Frame1G => Some((PAGE_SIZE_1G, PAGE_SIZE_1G)),
+        ObjectType::Notification => Some((size_of::<Notification>(), PAGE_SIZE_NORMAL)),
+        ObjectType::Endpoint => Some((size_of::<Endpoint>(), PAGE_SIZE_NORMAL)),
+        _ => None,
+    }
+}
+
+/// Builds an object of `object_type` in zeroed memory at `object_ptr` and
+/// returns the cap to it.
+pub fn init_object(object_type: &ObjectType, object_ptr: usize) -> Cap {
+    match object_type {
+        ObjectType::Frame4K => FrameCap::new(object_ptr, 3).into_cap(),
+        ObjectType::Frame2M => FrameCap::new(object_ptr, 2).into_cap(),
+        ObjectType::Frame1G => FrameCap::new(object_ptr, 1).into_cap(),
+        ObjectType::PageTable => PageTableCap::new(object_ptr).into_cap(),
+        ObjectType::Thread => {
+            let cap = ThreadCap::new(object_ptr);
+            let task = TaskContext::from_cap(&cap);
+            *task = TaskContext::new_user_thread();
+            task.init_cspace();
+            task.prio = MAX_THREAD_PIRO - 1;
+            cap.into_cap()
+        }
+        ObjectType::Notification => {
+            let cap = NotificationCap::new(object_ptr);
+            let notification = Notification::from_cap(&cap);
+            *notification = Notification::new();
+            cap.into_cap()
+        }
+        ObjectType::Endpoint => {
+            let cap = EndpointCap::new(object_ptr);
+            Endpoint::from_cap(&cap).init();
+            cap.into_cap()
+        }
+        ObjectType::CNode => {
+            // zeroed memory is an empty CapNode
+            let cap = CNodeCap::new(object_ptr);
+            cap.into_cap()
+        }
+        _ => {
+            unreachable!("object type without layout")
+        }
+    }
+}
+
+/// Deletes every cap in a CNode that is going away. Caps to the objects in
+/// `dying`, which are torn down by the caller, only leave the derivation tree.
+fn clear_cnode(cnode: &mut CapNode, dying: &[usize]) {
+    for i in 0..MAX_CNODE_SIZE {
+        if cnode.empty() {
+            break;
+        }
+        if !cnode.is_used(i) {
+            continue;
+        }
+        if mdb::cap_object(&cnode[i]).is_some_and(|object| dying.contains(&object)) {
+            mdb::forget_slot(cnode, i);
+        } else {
+            mdb::delete_slot(cnode, i);
         }
     }
 }
@@ -142,8 +498,13 @@ impl AllocHandler<'_> {
 pub struct DeallocHandler;
 
 impl CallbackHandler for DeallocHandler {
-    fn free_cnode(&self, _cap: CNodeCap) {
-        panic!("should not be invoked!")
+    fn free_cnode(&self, cap: CNodeCap) {
+        let cnode = cnode_from_cap(&cap);
+        clear_cnode(cnode, &[cnode as *const CapNode as usize]);
+        quota::forget(cnode);
+        let base_ptr = (cap.base_ptr() << 12) as usize;
+        let layout = Layout::from_size_align(size_of::<CapNode>(), PAGE_SIZE_NORMAL).unwrap();
+        free_memory(base_ptr, layout);
     }
 
     fn free_frame(&self, cap: FrameCap) {
@@ -155,48 +516,69 @@ impl CallbackHandler for DeallocHandler {
             2 => {
                 (PAGE_SIZE_2M, PAGE_SIZE_2M)
             }
+            1 => {
+                (PAGE_SIZE_1G, PAGE_SIZE_1G)
+            }
             _ => {
                 panic!("unsupported object type")
             }
         };
         let layout = Layout::from_size_align(size, align).unwrap();
-        unsafe {
-            dealloc(base_ptr as *mut u8, layout);
-        }
+        free_memory(base_ptr, layout);
     }
 
     fn free_page_table(&self, cap: PageTableCap) {
+        forget_vspace(PageTable::from_cap(&cap));
         let base_ptr = (cap.base_ptr() << 12) as usize;
         let layout = Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE_NORMAL).unwrap();
-        unsafe {
-            dealloc(base_ptr as *mut u8, layout);
-        }
+        free_memory(base_ptr, layout);
     }
 
     fn free_task(&self, cap: ThreadCap) {
         let task = TaskContext::from_cap(&cap);
-        if let Some(cspace) = task.cspace.take() {
-            for i in CNodeSlot::CapInitVSpace as usize..MAX_CNODE_SIZE {
-                if cspace.empty() {
-                    break;
-                }
-                if cspace.is_used(i) {
-                    cspace[i].free();
-                }
-            }
+        cancel_ipc(task);
+        cancel_wait(task);
+        cancel_reply(task);
+        if let Some(notification_cap) = task.bound_notification.take() {
+            Notification::from_cap(&notification_cap).unbind_task();
         }
-        let base_ptr = task.get_ptr();
-        let layout = Layout::from_size_align(size_of::<TaskContext>(), PAGE_SIZE_NORMAL).unwrap();
-        unsafe {
-            dealloc(base_ptr as *mut u8, layout);
+        task.state = ThreadStateEnum::ThreadStateInactive;
+        if let Some(cspace) = task.cspace.as_mut() {
+            let cspace_ptr = &**cspace as *const CapNode as usize;
+            clear_cnode(cspace, &[task.get_ptr(), cspace_ptr]);
+        }
+        if let Some(cspace) = task.cspace.as_ref() {
+            quota::forget(cspace);
         }
+        task.cspace = None;
+        defer_free_task(task);
     }
 
     fn free_notification(&self, cap: NotificationCap) {
+        ipc::notification::destroy(Notification::from_cap(&cap));
         let base_ptr = (cap.base_ptr() << 12) as usize;
         let layout = Layout::from_size_align(size_of::<Notification>(), PAGE_SIZE_NORMAL).unwrap();
+        free_memory(base_ptr, layout);
+    }
+
+    fn free_endpoint(&self, cap: EndpointCap) {
+        Endpoint::from_cap(&cap).destroy();
+        let base_ptr = (cap.base_ptr() << 12) as usize;
         unsafe {
-            dealloc(base_ptr as *mut u8, layout);
+            core::ptr::drop_in_place(base_ptr as *mut Endpoint);
+        }
+        let layout = Layout::from_size_align(size_of::<Endpoint>(), PAGE_SIZE_NORMAL).unwrap();
+        free_memory(base_ptr, layout);
+    }
+
+    /// Untyped memory is set aside at boot and never returned to the heap.
+    fn free_untyped(&self, _cap: UntypedCap) {}
+
+    fn free_reply(&self, cap: ReplyCap) {
+        let caller = unsafe { &mut *(((cap.base_ptr() << 12) as usize) as *mut TaskContext) };
+        forget_reply(caller);
+        if caller.state == ThreadStateEnum::ThreadStateBlockedOnReply {
+            caller.blocking_object = None;
         }
     }
 }
\ No newline at end of file
@@ -1,11 +1,37 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
 use mork_capability::cap::{Cap, CapType, PageTableCap};
-use mork_capability::cnode::{CapIndex, CapNode};
+use mork_capability::cnode::CapNode;
 use mork_common::mork_kernel_log;
 use mork_common::syscall::message_info::{InvocationLabel, MessageInfo, ResponseLabel};
 use mork_common::types::{ResultWithErr, VMRights};
+use mork_common::utils::alignas::is_aligned;
+use mork_hal::config::PAGE_SIZE_NORMAL;
 use mork_hal::context::HALContextTrait;
 use mork_mm::page_table::{MutPageTableWrapper, PageTable};
 use mork_task::task::TaskContext;
+use spin::Mutex;
+use crate::cspace::{resolve_address_bits, WORD_BITS};
+use crate::invocation::args::{cap_arg, CapArg};
+use crate::mdb;
+
+/// Every mapping of a frame or page table, keyed by the physical address of
+/// the object, the virtual address and the vspace it is mapped in. The value
+/// is the CapNode and index of the slot of the cap that made the mapping.
+static MAPPINGS: Mutex<BTreeMap<(usize, usize, usize), (usize, usize)>> = Mutex::new(BTreeMap::new());
+
+fn mapped_by(node: &CapNode, index: usize) -> (usize, usize) {
+    (node as *const CapNode as usize, index)
+}
+
+/// The vspace the cap in `slot` mapped `paddr` at `vaddr` in.
+fn find_mapping(paddr: usize, vaddr: usize, slot: (usize, usize)) -> Option<usize> {
+    MAPPINGS.lock().range((paddr, vaddr, 0)..=(paddr, vaddr, usize::MAX))
+        .find(|(_, by)| **by == slot)
+        .map(|((_, _, vspace), _)| *vspace)
+}
+
 
 pub fn handle(current: &mut TaskContext, dest_cap: PageTableCap, message_info: MessageInfo)
               -> ResultWithErr<MessageInfo> {
@@ -14,21 +40,26 @@ pub fn handle(current: &mut TaskContext, dest_cap: PageTableCap, message_info: M
     let vaddr = current.hal_context.get_mr(1);
     match InvocationLabel::from_usize(message_info.get_label()) {
         InvocationLabel::PageTableMap => {
-            let page_table_index = current.hal_context.get_mr(0);
-            page_table_map(cspace, page_table, page_table_index, vaddr)
+            let target = cap_arg(cspace, &mut current.hal_context, 0, CapType::PageTable)?;
+            page_table_map(target, page_table, vaddr)
         }
         InvocationLabel::PageTableUnmap => {
-            let page_table_index = current.hal_context.get_mr(0);
-            page_table_unmap(cspace, page_table, page_table_index)
+            let target = cap_arg(cspace, &mut current.hal_context, 0, CapType::PageTable)?;
+            page_table_unmap(target, page_table)
         }
         InvocationLabel::PageMap => {
-            let page_index = current.hal_context.get_mr(0);
             let vm_rights = current.hal_context.get_mr(2);
-            page_map(cspace, page_table, page_index, vaddr, vm_rights)
+            let target = cap_arg(cspace, &mut current.hal_context, 0, CapType::Frame)?;
+            page_map(target, page_table, vaddr, vm_rights)
         }
         InvocationLabel::PageUnmap => {
-            let page_index = current.hal_context.get_mr(0);
-            page_unmap(cspace, page_table, page_index)
+            let target = cap_arg(cspace, &mut current.hal_context, 0, CapType::Frame)?;
+            page_unmap(target, page_table)
+        }
+        InvocationLabel::PageRemap => {
+            let vm_rights = current.hal_context.get_mr(2);
+            let target = cap_arg(cspace, &mut current.hal_context, 0, CapType::Frame)?;
+            page_remap(target, page_table, vm_rights)
         }
         _ => {
             mork_kernel_log!(warn, "unSupported invocation label: {}", message_info.get_label());
@@ -37,16 +68,71 @@ pub fn handle(current: &mut TaskContext, dest_cap: PageTableCap, message_info: M
     }
 }
 
-fn page_table_map(cspace: &mut CapNode, vspace: &mut PageTable, target: CapIndex, vaddr: usize)
-                  -> ResultWithErr<MessageInfo> {
-    let page_table_cap = cspace[target];
-    if page_table_cap.get_type() != CapType::PageTable {
-        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
+/// Invocations made on the frame cap at `cptr` itself, so only authority over
+/// the frame is needed. `PageMap` still names the target vspace in MR0, while
+/// unmap and remap act on the vspace the frame is mapped in.
+pub fn handle_frame(current: &mut TaskContext, cptr: usize, message_info: MessageInfo)
+                    -> Result<usize, MessageInfo> {
+    let cspace = current.cspace.as_mut().unwrap();
+    let (node, slot) = resolve_address_bits(cspace, cptr, WORD_BITS)
+        .map_err(|fault| fault.report(&mut current.hal_context))?;
+    let cap = node[slot];
+    let target = CapArg { node, slot, cap };
+    let frame_cap = unsafe { cap.frame_cap };
+    match InvocationLabel::from_usize(message_info.get_label()) {
+        InvocationLabel::PageGetAddress => {
+            current.hal_context.set_mr(1, frame_cap.level() as usize);
+            Ok((frame_cap.base_ptr() << 12) as usize)
+        }
+        InvocationLabel::PageMap => {
+            let vaddr = current.hal_context.get_mr(1);
+            let vm_rights = current.hal_context.get_mr(2);
+            let vspace_cap = cap_arg(cspace, &mut current.hal_context, 0, CapType::PageTable)?.cap;
+            let vspace = PageTable::from_cap(&unsafe { vspace_cap.page_table_cap });
+            page_map(target, vspace, vaddr, vm_rights).map(|_| 0)
+        }
+        InvocationLabel::PageUnmap => {
+            let vspace = mapped_vspace(&target)?;
+            page_unmap(target, vspace).map(|_| 0)
+        }
+        InvocationLabel::PageRemap => {
+            let vm_rights = current.hal_context.get_mr(2);
+            let vspace = mapped_vspace(&target)?;
+            page_remap(target, vspace, vm_rights).map(|_| 0)
+        }
+        InvocationLabel::PageFlush => {
+            // caches are coherent on supported platforms, so a flush only
+            // has to order earlier accesses before the device sees the frame
+            fence(Ordering::SeqCst);
+            Ok(0)
+        }
+        _ => {
+            mork_kernel_log!(warn, "unSupported invocation label: {}", message_info.get_label());
+            Err(MessageInfo::new_response(ResponseLabel::UnSupported))
+        }
+    }
+}
+
+fn mapped_vspace(target: &CapArg) -> Result<&'static mut PageTable, MessageInfo> {
+    let frame_cap = unsafe { target.cap.frame_cap };
+    let paddr = (frame_cap.base_ptr() << 12) as usize;
+    let vaddr = (frame_cap.mapped_addr() << 12) as usize;
+    match find_mapping(paddr, vaddr, mapped_by(target.node, target.slot)) {
+        Some(vspace) if frame_cap.is_mapped() != 0 => Ok(unsafe { &mut *(vspace as *mut PageTable) }),
+        _ => {
+            mork_kernel_log!(warn, "frame is not mapped");
+            Err(MessageInfo::new_response(ResponseLabel::InvalidParam))
+        }
     }
-    let mut page_table_cap = unsafe { page_table_cap.page_table_cap };
+}
+
+fn page_table_map(mut target: CapArg, vspace: &mut PageTable, vaddr: usize)
+                  -> ResultWithErr<MessageInfo> {
+    let mut page_table_cap = unsafe { target.cap.page_table_cap };
     if page_table_cap.is_mapped() != 0 {
         return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
     }
+    let vspace_addr = vspace_ptr(vspace);
     let mut page_table_wrapper = MutPageTableWrapper::new(vspace);
     match page_table_wrapper.map_page_table(
         vaddr,
@@ -56,7 +142,9 @@ fn page_table_map(cspace: &mut CapNode, vspace: &mut PageTable, target: CapIndex
             page_table_cap.set_mapped(1);
             page_table_cap.set_mapped_addr(vaddr as u128 >> 12);
             page_table_cap.set_level(level as u128);
-            cspace[target] = Cap { page_table_cap };
+            target.update(Cap { page_table_cap });
+            let paddr = (page_table_cap.base_ptr() << 12) as usize;
+            MAPPINGS.lock().insert((paddr, vaddr, vspace_addr), mapped_by(target.node, target.slot));
             Ok(())
         }
         Err(err) => {
@@ -65,26 +153,28 @@ fn page_table_map(cspace: &mut CapNode, vspace: &mut PageTable, target: CapIndex
     }
 }
 
-fn page_table_unmap(cspace: &mut CapNode, vspace: &mut PageTable, target: CapIndex)
+fn page_table_unmap(mut target: CapArg, vspace: &mut PageTable)
     -> ResultWithErr<MessageInfo> {
-    let page_table_cap = cspace[target];
-    if page_table_cap.get_type() != CapType::PageTable {
-        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
-    }
-    let mut page_table_cap = unsafe { page_table_cap.page_table_cap };
+    let mut page_table_cap = unsafe { target.cap.page_table_cap };
     let level = page_table_cap.mapped_level() as usize;
     if page_table_cap.is_mapped() == 0 || level == 0 {
         return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
     }
     let vaddr = (page_table_cap.mapped_addr() << 12) as usize;
     let paddr = (page_table_cap.base_ptr() << 12) as usize;
+    let vspace_addr = vspace_ptr(vspace);
+    if find_mapping(paddr, vaddr, mapped_by(target.node, target.slot)) != Some(vspace_addr) {
+        mork_kernel_log!(warn, "page table is not mapped in this vspace");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
     let mut page_table_wrapper = MutPageTableWrapper::new(vspace);
     match page_table_wrapper.unmap_page_table(vaddr, paddr, level) {
         Ok(_) => {
+            MAPPINGS.lock().remove(&(paddr, vaddr, vspace_addr));
             page_table_cap.set_mapped(0);
             page_table_cap.set_mapped_addr(0);
             page_table_cap.set_level(0);
-            cspace[target] = Cap { page_table_cap };
+            target.update(Cap { page_table_cap });
             Ok(())
         }
         Err(resp) => {
@@ -93,8 +183,7 @@ fn page_table_unmap(cspace: &mut CapNode, vspace: &mut PageTable, target: CapInd
     }
 }
 
-fn page_map(cspace: &mut CapNode, vspace: &mut PageTable,
-            target: CapIndex, vaddr: usize, vm_rights_arg: usize)
+fn page_map(mut target: CapArg, vspace: &mut PageTable, vaddr: usize, vm_rights_arg: usize)
             -> ResultWithErr<MessageInfo> {
     let vm_rights = VMRights::from_bits(vm_rights_arg as u8);
     if vm_rights.is_none() {
@@ -102,15 +191,23 @@ fn page_map(cspace: &mut CapNode, vspace: &mut PageTable,
         return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
     }
     let vm_rights = vm_rights.unwrap();
-    let frame_cap = cspace[target];
-
-    if frame_cap.get_type() != CapType::Frame {
-        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
-    }
-    let mut frame_cap = unsafe { frame_cap.frame_cap };
+    let mut frame_cap = unsafe { target.cap.frame_cap };
     if frame_cap.is_mapped() != 0 {
         return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
     }
+    // Otherwise device frames are mapped like RAM: on RISC-V without Svpbmt the
+    // PTE carries no memory type, and the platform's PMAs already make device
+    // ranges uncached and strongly ordered.
+    if frame_cap.is_device() != 0 && vm_rights.contains(VMRights::X) {
+        mork_kernel_log!(warn, "device frames can not be mapped executable");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
+    let frame_size = PAGE_SIZE_NORMAL << (9 * (3 - frame_cap.level() as usize));
+    if !is_aligned(vaddr, frame_size) {
+        mork_kernel_log!(warn, "vaddr {:#x} is not aligned to the frame size {:#x}", vaddr, frame_size);
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
+    let vspace_addr = vspace_ptr(vspace);
     let mut page_table_wrapper = MutPageTableWrapper::new(vspace);
     match page_table_wrapper.map_frame(
         vaddr,
@@ -123,7 +220,9 @@ fn page_map(cspace: &mut CapNode, vspace: &mut PageTable,
         Ok(_) => {
             frame_cap.set_mapped(1);
             frame_cap.set_mapped_addr(vaddr as u128 >> 12);
-            cspace[target] = Cap { frame_cap };
+            target.update(Cap { frame_cap });
+            let paddr = (frame_cap.base_ptr() << 12) as usize;
+            MAPPINGS.lock().insert((paddr, vaddr, vspace_addr), mapped_by(target.node, target.slot));
             Ok(())
         }
         Err(resp) => {
@@ -132,27 +231,152 @@ fn page_map(cspace: &mut CapNode, vspace: &mut PageTable,
     }
 }
 
-fn page_unmap(cspace: &mut CapNode, vspace: &mut PageTable, target: CapIndex)
+/// Changes the rights of a mapped frame by rewriting its leaf entry in place,
+/// then flushing the stale TLB entry. The frame stays mapped throughout, but
+/// another hart may use the old rights until its TLB entry is shot down.
+fn page_remap(target: CapArg, vspace: &mut PageTable, vm_rights_arg: usize)
               -> ResultWithErr<MessageInfo> {
-    let frame_cap = cspace[target];
-    if frame_cap.get_type() != CapType::Frame {
-        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
+    let Some(vm_rights) = u8::try_from(vm_rights_arg).ok().and_then(VMRights::from_bits) else {
+        mork_kernel_log!(warn, "Invalid vm_rights: {}", vm_rights_arg);
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    };
+    let frame_cap = unsafe { target.cap.frame_cap };
+    if frame_cap.is_device() != 0 && vm_rights.contains(VMRights::X) {
+        mork_kernel_log!(warn, "device frames can not be mapped executable");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
+    let paddr = (frame_cap.base_ptr() << 12) as usize;
+    let vaddr = (frame_cap.mapped_addr() << 12) as usize;
+    let slot = mapped_by(target.node, target.slot);
+    let vspace_addr = vspace_ptr(vspace);
+    if frame_cap.is_mapped() == 0 || find_mapping(paddr, vaddr, slot) != Some(vspace_addr) {
+        mork_kernel_log!(warn, "frame is not mapped in this vspace");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
+    let mut page_table_wrapper = MutPageTableWrapper::new(vspace);
+    match page_table_wrapper.set_frame_rights(
+        vaddr,
+        vm_rights.contains(VMRights::X),
+        vm_rights.contains(VMRights::W),
+        vm_rights.contains(VMRights::R),
+    ) {
+        Ok(_) => Ok(()),
+        Err(resp) => {
+            Err(MessageInfo::new_response(resp))
+        }
     }
-    let mut frame_cap = unsafe { frame_cap.frame_cap };
+}
+
+fn page_unmap(mut target: CapArg, vspace: &mut PageTable)
+              -> ResultWithErr<MessageInfo> {
+    let mut frame_cap = unsafe { target.cap.frame_cap };
     if frame_cap.is_mapped() == 0 {
         return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
     }
     let mapped_vaddr = (frame_cap.mapped_addr() << 12) as usize;
+    let paddr = (frame_cap.base_ptr() << 12) as usize;
+    let vspace_addr = vspace_ptr(vspace);
+    if find_mapping(paddr, mapped_vaddr, mapped_by(target.node, target.slot)) != Some(vspace_addr) {
+        mork_kernel_log!(warn, "frame is not mapped in this vspace");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
     let mut page_table_wrapper = MutPageTableWrapper::new(vspace);
     match page_table_wrapper.unmap_frame(mapped_vaddr) {
         Ok(_) => {
+            MAPPINGS.lock().remove(&(paddr, mapped_vaddr, vspace_addr));
             frame_cap.set_mapped_addr(0);
             frame_cap.set_mapped(0);
-            cspace[target] = Cap { frame_cap };
+            target.update(Cap { frame_cap });
             Ok(())
         }
         Err(resp) => {
             Err(MessageInfo::new_response(resp))
         }
     }
+}
+
+fn vspace_ptr(vspace: &PageTable) -> usize {
+    vspace as *const PageTable as usize
+}
+
+/// Removes the mapping the cap in `node[index]` still holds, so the object can
+/// be released without leaving a dangling entry in its vspace.
+pub fn unmap_slot(node: &CapNode, index: usize) {
+    let cap = node[index];
+    let (paddr, vaddr) = match cap.get_type() {
+        CapType::Frame => {
+            let frame_cap = unsafe { cap.frame_cap };
+            if frame_cap.is_mapped() == 0 {
+                return;
+            }
+            ((frame_cap.base_ptr() << 12) as usize, (frame_cap.mapped_addr() << 12) as usize)
+        }
+        CapType::PageTable => {
+            let page_table_cap = unsafe { cap.page_table_cap };
+            if page_table_cap.is_mapped() == 0 || page_table_cap.mapped_level() == 0 {
+                return;
+            }
+            ((page_table_cap.base_ptr() << 12) as usize, (page_table_cap.mapped_addr() << 12) as usize)
+        }
+        _ => return,
+    };
+    let Some(vspace) = find_mapping(paddr, vaddr, mapped_by(node, index)) else {
+        return;
+    };
+    MAPPINGS.lock().remove(&(paddr, vaddr, vspace));
+    let vspace = unsafe { &mut *(vspace as *mut PageTable) };
+    if cap.get_type() == CapType::Frame {
+        if MutPageTableWrapper::new(vspace).unmap_frame(vaddr).is_err() {
+            mork_kernel_log!(warn, "unmap frame {:#x} failed", vaddr);
+        }
+    } else {
+        let level = unsafe { cap.page_table_cap }.mapped_level() as usize;
+        if MutPageTableWrapper::new(vspace).unmap_page_table(vaddr, paddr, level).is_err() {
+            mork_kernel_log!(warn, "unmap page table {:#x} failed", vaddr);
+        }
+    }
+}
+
+/// Follows a mapped cap that moved from `src_node[src_index]` to
+/// `dest_node[dest_index]`.
+pub fn relocate_mapping(src_node: &CapNode, src_index: usize, dest_node: &CapNode, dest_index: usize) {
+    let (from, to) = (mapped_by(src_node, src_index), mapped_by(dest_node, dest_index));
+    for slot in MAPPINGS.lock().values_mut() {
+        if *slot == from {
+            *slot = to;
+        }
+    }
+}
+
+/// Drops every mapping recorded against a vspace whose memory is being freed,
+/// and marks the frame and page table caps that made them as unmapped so they
+/// can be mapped again elsewhere.
+pub fn forget_vspace(vspace: &PageTable) {
+    let vspace = vspace_ptr(vspace);
+    let mut forgotten = Vec::new();
+    MAPPINGS.lock().retain(|(_, _, mapped_in), slot| {
+        if *mapped_in == vspace {
+            forgotten.push(*slot);
+        }
+        *mapped_in != vspace
+    });
+    for (cnode, index) in forgotten {
+        let node = unsafe { &mut *(cnode as *mut CapNode) };
+        let mut cap = node[index];
+        match cap.get_type() {
+            CapType::Frame => {
+                let frame_cap = unsafe { &mut cap.frame_cap };
+                frame_cap.set_mapped_addr(0);
+                frame_cap.set_mapped(0);
+            }
+            CapType::PageTable => {
+                let page_table_cap = unsafe { &mut cap.page_table_cap };
+                page_table_cap.set_mapped(0);
+                page_table_cap.set_mapped_addr(0);
+                page_table_cap.set_level(0);
+            }
+            _ => continue,
+        }
+        mdb::update_slot(node, index, cap);
+    }
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
+pub mod args;
 pub mod task_handler;
 pub mod memory_handler;
-pub mod cspace_handler;
\ No newline at end of file
+pub mod cspace_handler;
+pub mod untyped_handler;
\ No newline at end of file
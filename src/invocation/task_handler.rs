@@ -6,17 +6,22 @@ use mork_common::mork_kernel_log;
 use mork_common::syscall::message_info::{InvocationLabel, MessageInfo, ResponseLabel};
 use mork_common::utils::alignas::is_aligned;
 use mork_hal::context::HALContextTrait;
+use mork_ipc::notification::Notification;
 use mork_kernel_state::KernelSafeAccessData;
 use mork_mm::page_table::{map_kernel_window, PageTableWrapper, PageTable};
 use mork_task::task::TaskContext;
 use mork_task::task_state::ThreadStateEnum;
+use crate::invocation::args::cap_arg;
+use crate::ipc;
+use crate::mdb;
 
 pub fn handle(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext,
               dest_cap: ThreadCap, message_info: MessageInfo)
               -> Result<usize, MessageInfo> {
     let task = TaskContext::from_cap(&dest_cap);
-    if message_info.get_label() >= InvocationLabel::CNodeAlloc as usize
-        && message_info.get_label() <= InvocationLabel::CNodeSaveCaller as usize {
+    let label = message_info.get_label();
+    if (label >= InvocationLabel::CNodeAlloc as usize && label <= InvocationLabel::CNodeSaveCaller as usize)
+        || label == InvocationLabel::CNodeAllocChild as usize {
         return super::cspace_handler::handle(current, dest_cap, message_info);
     }
     match InvocationLabel::from_usize(message_info.get_label()) {
@@ -74,6 +79,14 @@ pub fn handle(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext
             handle_write_registers(task, current)
         }
 
+        InvocationLabel::TCBBindNotification => {
+            handle_bind_notification(task, current)
+        }
+
+        InvocationLabel::TCBUnbindNotification => {
+            handle_unbind_notification(task)
+        }
+
         _ => {
             mork_kernel_log!(warn, "unSupported invocation label: {}", message_info.get_label());
             Err(MessageInfo::new_response(ResponseLabel::UnSupported))
@@ -112,23 +125,52 @@ fn handle_write_registers(task: &mut TaskContext, current: &mut TaskContext) ->
 fn handle_set_space(task: &mut TaskContext, current: &mut TaskContext) -> Result<usize, MessageInfo> {
     let is_current = task.get_ptr() == current.get_ptr();
     let cspace = current.cspace.as_mut().unwrap();
-    let vspace_cap = cspace[current.hal_context.get_mr(0)];
-    if vspace_cap.get_type() != CapType::PageTable {
-        mork_kernel_log!(warn, "Invalid cap type: {:?}", vspace_cap.get_type());
-        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
-    }
+    let vspace = cap_arg(cspace, &mut current.hal_context, 0, CapType::PageTable)?;
+    let vspace_cap = vspace.cap;
     if is_current {
         if current.hal_context.get_mr(0) != CNodeSlot::CapInitVSpace as usize {
-            cspace.free_slot(CNodeSlot::CapInitVSpace as usize);
-            cspace[CNodeSlot::CapInitVSpace as usize] = vspace_cap.derive();
+            if mdb::is_last_cap(cspace, CNodeSlot::CapInitVSpace as usize) {
+                mork_kernel_log!(warn, "the running vspace would be freed, keep another cap to it");
+                return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+            }
+            mdb::delete_slot(cspace, CNodeSlot::CapInitVSpace as usize);
+            mdb::insert_derived(vspace.node, vspace.slot, cspace, CNodeSlot::CapInitVSpace as usize, vspace_cap.derive());
         }
     } else {
         let target_cspace = task.cspace.as_mut().unwrap();
-        target_cspace.free_slot(CNodeSlot::CapInitVSpace as usize);
+        mdb::delete_slot(target_cspace, CNodeSlot::CapInitVSpace as usize);
         let page_table_cap = unsafe { vspace_cap.page_table_cap};
         let page_table = PageTable::from_cap(&page_table_cap );
         map_kernel_window(page_table).unwrap();
-        target_cspace[CNodeSlot::CapInitVSpace as usize] = vspace_cap.derive();
+        mdb::insert_derived(vspace.node, vspace.slot, target_cspace, CNodeSlot::CapInitVSpace as usize, vspace_cap.derive());
+    }
+    Ok(0)
+}
+
+fn handle_bind_notification(task: &mut TaskContext, current: &mut TaskContext) -> Result<usize, MessageInfo> {
+    let cspace = current.cspace.as_mut().unwrap();
+    let notification_cap = cap_arg(cspace, &mut current.hal_context, 0, CapType::Notification)?.cap;
+    if !ipc::can_receive(&notification_cap) {
+        mork_kernel_log!(warn, "notification cap can not receive");
+        return Err(MessageInfo::new_response(ResponseLabel::ErrCapType));
+    }
+    let notification_cap = unsafe { notification_cap.notification_cap };
+    let notification = Notification::from_cap(&notification_cap);
+    if task.bound_notification.is_some() || notification.bound_task().is_some() {
+        mork_kernel_log!(warn, "task or notification is already bound");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
     }
+    notification.bind_task(task.get_ptr());
+    task.bound_notification = Some(notification_cap);
     Ok(0)
+}
+
+fn handle_unbind_notification(task: &mut TaskContext) -> Result<usize, MessageInfo> {
+    if let Some(notification_cap) = task.bound_notification.take() {
+        Notification::from_cap(&notification_cap).unbind_task();
+        Ok(0)
+    } else {
+        mork_kernel_log!(warn, "task has no bound notification");
+        Err(MessageInfo::new_response(ResponseLabel::InvalidParam))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,145 @@
+use alloc::alloc::dealloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use mork_capability::cap::{Cap, CapType, UntypedCap};
+use mork_capability::cnode::CapNode;
+use mork_common::constants::ObjectType;
+use mork_common::mork_kernel_log;
+use mork_common::syscall::message_info::{InvocationLabel, MessageInfo, ResponseLabel};
+use mork_common::utils::alignas::is_aligned;
+use mork_hal::config::PAGE_SIZE_NORMAL;
+use mork_hal::context::HALContextTrait;
+use mork_task::task::TaskContext;
+use spin::Mutex;
+use crate::cspace::{resolve_address_bits, WORD_BITS};
+use crate::invocation::args::empty_slot_arg;
+use crate::invocation::cspace_handler::{adopt_thread, get_layout, init_object, quota_overhead};
+use crate::mdb;
+use crate::quota;
+
+/// Memory regions handed out as untyped caps. Objects inside them belong to
+/// their untyped and are never returned to the kernel heap.
+static UNTYPED_REGIONS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+/// Places an untyped cap covering `1 << size_bits` bytes at `base` in a free
+/// slot of `cspace`. Called at boot for every region set aside for user space.
+pub fn create_untyped(cspace: &mut CapNode, base: usize, size_bits: usize) -> Option<usize> {
+    create(cspace, base, size_bits, false)
+}
+
+/// Like `create_untyped`, for a physical device range such as a UART or
+/// virtio-mmio window. It can only be retyped into frames, which are never
+/// zeroed.
+pub fn create_device_untyped(cspace: &mut CapNode, base: usize, size_bits: usize) -> Option<usize> {
+    create(cspace, base, size_bits, true)
+}
+
+fn create(cspace: &mut CapNode, base: usize, size_bits: usize, is_device: bool) -> Option<usize> {
+    if size_bits < PAGE_SIZE_NORMAL.trailing_zeros() as usize || !is_aligned(base, 1 << size_bits) {
+        mork_kernel_log!(warn, "Invalid untyped region {:#x}, size bits: {}", base, size_bits);
+        return None;
+    }
+    let slot = cspace.alloc_free()?;
+    let mut untyped_cap = UntypedCap::new(base, size_bits);
+    untyped_cap.set_is_device(is_device as u128);
+    mdb::insert_root(cspace, slot, untyped_cap.into_cap());
+    UNTYPED_REGIONS.lock().push((base, 1 << size_bits));
+    Some(slot)
+}
+
+/// Returns the memory of a freed object to the kernel heap unless it was
+/// carved out of an untyped region, and credits what it was charged.
+pub fn free_memory(ptr: usize, layout: Layout) {
+    quota::credit(ptr);
+    let in_untyped = UNTYPED_REGIONS.lock().iter()
+        .any(|(base, size)| ptr >= *base && ptr < base + size);
+    if !in_untyped {
+        unsafe {
+            dealloc(ptr as *mut u8, layout);
+        }
+    }
+}
+
+pub fn handle(current: &mut TaskContext, cptr: usize, message_info: MessageInfo) -> Result<usize, MessageInfo> {
+    let cspace = current.cspace.as_mut().unwrap();
+    let (node, slot) = resolve_address_bits(cspace, cptr, WORD_BITS)
+        .map_err(|fault| fault.report(&mut current.hal_context))?;
+    match InvocationLabel::from_usize(message_info.get_label()) {
+        InvocationLabel::UntypedRetype => {
+            let object_type = ObjectType::from_usize(current.hal_context.get_mr(0));
+            let (dest_node, dest_slot) = empty_slot_arg(cspace, &mut current.hal_context, 1)?;
+            retype(node, slot, &object_type, dest_node, dest_slot, cspace)?;
+            Ok(current.hal_context.get_mr(1))
+        }
+        _ => {
+            mork_kernel_log!(warn, "unSupported invocation label: {}", message_info.get_label());
+            Err(MessageInfo::new_response(ResponseLabel::UnSupported))
+        }
+    }
+}
+
+/// Creates an object of `object_type` at the watermark of the untyped cap in
+/// `node[slot]` and stores its cap, derived from the untyped, in `dest_node`.
+/// An untyped without live children starts again from its base, as seL4 does.
+/// Untyped caps are never copied, so the watermark covers the whole region.
+/// Heap memory the object allocates for itself is charged to `cspace`.
+fn retype(node: &mut CapNode, slot: usize, object_type: &ObjectType,
+          dest_node: &mut CapNode, dest_slot: usize, cspace: &CapNode) -> Result<(), MessageInfo> {
+    let Some((size, align)) = get_layout(object_type) else {
+        mork_kernel_log!(warn, "unsupported object type");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    };
+    let mut untyped_cap = unsafe { node[slot].untyped_cap };
+    let is_device = untyped_cap.is_device() != 0;
+    let is_frame = matches!(object_type, ObjectType::Frame4K | ObjectType::Frame2M | ObjectType::Frame1G);
+    if is_device && !is_frame {
+        mork_kernel_log!(warn, "device untyped can only be retyped into frames");
+        return Err(MessageInfo::new_response(ResponseLabel::InvalidParam));
+    }
+    let base = (untyped_cap.base_ptr() << 12) as usize;
+    let end = base + (1 << untyped_cap.block_size());
+    let free = if mdb::has_children(node, slot) {
+        base + ((untyped_cap.free_index() << 12) as usize)
+    } else {
+        base
+    };
+    let object_ptr = free.next_multiple_of(align);
+    if object_ptr + size > end {
+        mork_kernel_log!(warn, "untyped {:#x} has no room for {} bytes", base, size);
+        return Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace));
+    }
+    let overhead = quota_overhead(object_type);
+    if !quota::try_charge(cspace, overhead) {
+        mork_kernel_log!(warn, "cspace memory quota exceeded");
+        return Err(MessageInfo::new_response(ResponseLabel::NotEnoughSpace));
+    }
+    if overhead > 0 {
+        quota::record(cspace, object_ptr, overhead);
+    }
+    let cap = if is_device {
+        let mut frame_cap = unsafe { init_object(object_type, object_ptr).frame_cap };
+        frame_cap.set_is_device(1);
+        Cap { frame_cap }
+    } else {
+        unsafe {
+            core::ptr::write_bytes(object_ptr as *mut u8, 0, size);
+        }
+        init_object(object_type, object_ptr)
+    };
+    let watermark = (object_ptr + size).next_multiple_of(PAGE_SIZE_NORMAL) - base;
+    untyped_cap.set_free_index((watermark >> 12) as u128);
+    mdb::update_slot(node, slot, Cap { untyped_cap });
+    mdb::insert_derived(node, slot, dest_node, dest_slot, cap);
+    adopt_thread(&dest_node[dest_slot], cspace);
+    Ok(())
+}
+
+/// Rewinds the watermark of an untyped cap whose children were all revoked.
+/// It is left alone while any object carved from it is still alive.
+pub fn reset(node: &mut CapNode, slot: usize) {
+    if node[slot].get_type() == CapType::Untyped && !mdb::has_children(node, slot) {
+        let mut untyped_cap = unsafe { node[slot].untyped_cap };
+        untyped_cap.set_free_index(0);
+        mdb::update_slot(node, slot, Cap { untyped_cap });
+    }
+}
\ No newline at end of file
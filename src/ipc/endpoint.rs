@@ -0,0 +1,155 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use mork_capability::cap::EndpointCap;
+use mork_hal::context::HALContextTrait;
+use mork_common::syscall::message_info::{MessageInfo, ResponseLabel};
+use mork_task::task::TaskContext;
+use mork_task::task_state::ThreadStateEnum;
+use crate::ipc::reply::setup_caller;
+use crate::ipc::transfer::do_ipc_transfer;
+use crate::reaper::defer_wake;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointState {
+    Idle,
+    Send,
+    Recv,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EndpointWaiter {
+    task: usize,
+    badge: usize,
+    can_grant: bool,
+    is_call: bool,
+}
+
+pub struct Endpoint {
+    state: EndpointState,
+    queue: VecDeque<EndpointWaiter>,
+}
+
+impl Endpoint {
+    pub fn new() -> Self {
+        Self {
+            state: EndpointState::Idle,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn from_cap(cap: &EndpointCap) -> &'static mut Self {
+        let ptr = (cap.base_ptr() << 12) as usize;
+        unsafe { &mut *(ptr as *mut Self) }
+    }
+
+    /// Writes a fresh endpoint over zeroed memory without dropping the old contents.
+    pub fn init(&mut self) {
+        unsafe { core::ptr::write(self as *mut Self, Self::new()) }
+    }
+
+    fn get_ptr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Sends the message in `current`'s registers. Returns the receiver to be
+    /// scheduled if one was waiting. Otherwise `current` is blocked on the
+    /// endpoint, or the message is dropped when `blocking` is false.
+    /// Extra caps are only transferred when `can_grant` is set.
+    pub fn send(&mut self, current: &mut TaskContext, badge: usize, can_grant: bool,
+                blocking: bool, is_call: bool) -> Option<Box<TaskContext>> {
+        if self.state == EndpointState::Recv {
+            let waiter = self.dequeue();
+            let mut receiver = unsafe { Box::from_raw(waiter.task as *mut TaskContext) };
+            do_ipc_transfer(current, &mut receiver, badge, can_grant);
+            if is_call {
+                setup_caller(&mut receiver, current);
+            }
+            receiver.state = ThreadStateEnum::ThreadStateRestart;
+            return Some(receiver);
+        }
+        if blocking {
+            current.state = ThreadStateEnum::ThreadStateBlockedOnSend;
+            current.blocking_object = Some(self.get_ptr());
+            self.queue.push_back(EndpointWaiter { task: current.get_ptr(), badge, can_grant, is_call });
+            self.state = EndpointState::Send;
+        }
+        None
+    }
+
+    /// Receives a message into `current`'s registers. Returns the sender to be
+    /// scheduled if it did a plain send; a caller stays blocked until replied.
+    /// If no sender is waiting, `current` is blocked on the endpoint, or a
+    /// `WouldBlock` response is returned when `blocking` is false.
+    pub fn receive(&mut self, current: &mut TaskContext, blocking: bool)
+        -> Result<Option<Box<TaskContext>>, MessageInfo> {
+        if self.state == EndpointState::Send {
+            let waiter = self.dequeue();
+            let mut sender = unsafe { Box::from_raw(waiter.task as *mut TaskContext) };
+            do_ipc_transfer(&sender, current, waiter.badge, waiter.can_grant);
+            if waiter.is_call {
+                setup_caller(current, &mut sender);
+                Box::leak(sender);
+                return Ok(None);
+            }
+            sender.hal_context.set_tag(MessageInfo::new_response(ResponseLabel::Success));
+            sender.state = ThreadStateEnum::ThreadStateRestart;
+            return Ok(Some(sender));
+        }
+        if !blocking {
+            return Err(MessageInfo::new_response(ResponseLabel::WouldBlock));
+        }
+        current.state = ThreadStateEnum::ThreadStateBlockedOnReceive;
+        current.blocking_object = Some(self.get_ptr());
+        self.queue.push_back(EndpointWaiter {
+            task: current.get_ptr(),
+            badge: 0,
+            can_grant: false,
+            is_call: false,
+        });
+        self.state = EndpointState::Recv;
+        Ok(None)
+    }
+
+    /// Removes `task` from the wait queue without completing its IPC.
+    pub fn cancel(&mut self, task: &mut TaskContext) {
+        let task_ptr = task.get_ptr();
+        self.queue.retain(|waiter| waiter.task != task_ptr);
+        if self.queue.is_empty() {
+            self.state = EndpointState::Idle;
+        }
+        task.blocking_object = None;
+    }
+
+    /// Wakes every waiter with `ErrCapType` before the endpoint is freed.
+    pub fn destroy(&mut self) {
+        while !self.queue.is_empty() {
+            let waiter = self.dequeue();
+            let mut task = unsafe { Box::from_raw(waiter.task as *mut TaskContext) };
+            task.hal_context.set_tag(MessageInfo::new_response(ResponseLabel::ErrCapType));
+            task.state = ThreadStateEnum::ThreadStateRestart;
+            defer_wake(task);
+        }
+    }
+
+    fn dequeue(&mut self) -> EndpointWaiter {
+        let waiter = self.queue.pop_front().unwrap();
+        if self.queue.is_empty() {
+            self.state = EndpointState::Idle;
+        }
+        let task = unsafe { &mut *(waiter.task as *mut TaskContext) };
+        task.blocking_object = None;
+        waiter
+    }
+}
+
+/// Takes `task` off the endpoint it is blocked on, if any.
+pub fn cancel_ipc(task: &mut TaskContext) {
+    if task.state != ThreadStateEnum::ThreadStateBlockedOnSend
+        && task.state != ThreadStateEnum::ThreadStateBlockedOnReceive {
+        return;
+    }
+    if let Some(endpoint_ptr) = task.blocking_object {
+        let endpoint = unsafe { &mut *(endpoint_ptr as *mut Endpoint) };
+        endpoint.cancel(task);
+    }
+}
\ No newline at end of file
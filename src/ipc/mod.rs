@@ -0,0 +1,22 @@
+pub mod endpoint;
+pub mod notification;
+pub mod reply;
+pub mod transfer;
+
+use mork_capability::cap::{Cap, CapType};
+
+pub fn can_send(cap: &Cap) -> bool {
+    match cap.get_type() {
+        CapType::Notification => unsafe { cap.notification_cap }.can_send() != 0,
+        CapType::Endpoint => unsafe { cap.endpoint_cap }.can_send() != 0,
+        _ => true,
+    }
+}
+
+pub fn can_receive(cap: &Cap) -> bool {
+    match cap.get_type() {
+        CapType::Notification => unsafe { cap.notification_cap }.can_receive() != 0,
+        CapType::Endpoint => unsafe { cap.endpoint_cap }.can_receive() != 0,
+        _ => true,
+    }
+}
\ No newline at end of file
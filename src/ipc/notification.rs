@@ -0,0 +1,83 @@
+use alloc::boxed::Box;
+use mork_common::syscall::message_info::{MessageInfo, ResponseLabel};
+use mork_hal::context::HALContextTrait;
+use mork_ipc::notification::Notification;
+use mork_task::task::TaskContext;
+use mork_task::task_state::ThreadStateEnum;
+use crate::ipc::endpoint::cancel_ipc;
+use crate::ipc::transfer::deliver_badge;
+use crate::reaper::defer_wake;
+
+/// Signals `notification`, returning the thread to be scheduled if one was
+/// woken. A bound thread blocked in an endpoint receive takes precedence and
+/// is told through the `BoundNotification` response label that the
+/// notification fired rather than the endpoint.
+pub fn signal(notification: &mut Notification, badge: usize) -> Option<Box<TaskContext>> {
+    if let Some(task_ptr) = notification.bound_task() {
+        let task = unsafe { &mut *(task_ptr as *mut TaskContext) };
+        if task.state == ThreadStateEnum::ThreadStateBlockedOnReceive {
+            cancel_ipc(task);
+            let mut task = unsafe { Box::from_raw(task_ptr as *mut TaskContext) };
+            deliver_badge(&mut task, badge);
+            task.hal_context.set_tag(MessageInfo::new_response(ResponseLabel::BoundNotification));
+            task.state = ThreadStateEnum::ThreadStateRestart;
+            return Some(task);
+        }
+    }
+    let mut task = notification.signal(badge)?;
+    task.blocking_object = None;
+    deliver_badge(&mut task, badge);
+    Some(task)
+}
+
+/// Completes an endpoint receive from the bound notification of `current`
+/// when it already has a pending signal.
+pub fn poll_bound(current: &mut TaskContext) -> bool {
+    let Some(notification_cap) = current.bound_notification else {
+        return false;
+    };
+    if let Some(badge) = Notification::from_cap(&notification_cap).poll() {
+        deliver_badge(current, badge);
+        current.hal_context.set_tag(MessageInfo::new_response(ResponseLabel::BoundNotification));
+        true
+    } else {
+        false
+    }
+}
+
+/// Receives on `notification`, remembering it as the blocking object of
+/// `current` when no signal is pending so a later teardown can find it.
+pub fn receive(notification: &mut Notification, current: &mut TaskContext) -> Option<usize> {
+    let badge = notification.receive(current);
+    if badge.is_none() {
+        current.blocking_object = Some(notification as *mut Notification as usize);
+    }
+    badge
+}
+
+/// Takes `task` off the notification it is waiting on, if any.
+pub fn cancel_wait(task: &mut TaskContext) {
+    if task.state != ThreadStateEnum::ThreadStateBlockedOnNotification {
+        return;
+    }
+    if let Some(notification_ptr) = task.blocking_object.take() {
+        let notification = unsafe { &mut *(notification_ptr as *mut Notification) };
+        notification.cancel(task.get_ptr());
+    }
+}
+
+/// Detaches everything still referring to a notification about to be freed.
+/// The bound thread is unbound and waiters are woken with `ErrCapType`.
+pub fn destroy(notification: &mut Notification) {
+    if let Some(task_ptr) = notification.bound_task() {
+        let task = unsafe { &mut *(task_ptr as *mut TaskContext) };
+        task.bound_notification = None;
+        notification.unbind_task();
+    }
+    for mut task in notification.take_waiters() {
+        task.blocking_object = None;
+        task.hal_context.set_tag(MessageInfo::new_response(ResponseLabel::ErrCapType));
+        task.state = ThreadStateEnum::ThreadStateRestart;
+        defer_wake(task);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,100 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use mork_capability::cap::{Cap, CapType, ReplyCap};
+use mork_capability::cnode::CapNode;
+use mork_common::constants::CNodeSlot;
+use mork_common::mork_kernel_log;
+use mork_task::task::TaskContext;
+use mork_task::task_state::ThreadStateEnum;
+use spin::Mutex;
+use crate::cspace::{resolve_address_bits, CNODE_RADIX_BITS};
+use crate::ipc::transfer::do_ipc_transfer;
+use crate::mdb;
+
+/// The slot holding the reply cap of every caller blocked on reply, keyed by
+/// the address of the caller. A caller has at most one reply cap.
+static REPLY_SLOTS: Mutex<BTreeMap<usize, (usize, usize)>> = Mutex::new(BTreeMap::new());
+
+fn reply_cap_caller(cap: &Cap) -> usize {
+    (unsafe { cap.reply_cap }.base_ptr() << 12) as usize
+}
+
+pub fn setup_caller(receiver: &mut TaskContext, caller: &mut TaskContext) {
+    caller.state = ThreadStateEnum::ThreadStateBlockedOnReply;
+    caller.blocking_object = Some(receiver.get_ptr());
+    if let Some(cspace) = receiver.cspace.as_mut() {
+        let slot = CNodeSlot::CapReply as usize;
+        if cspace.is_used(slot) {
+            // user space may have parked any cap here, so delete it properly
+            mdb::delete_slot(cspace, slot);
+        }
+        mdb::insert_root(cspace, slot, ReplyCap::new(caller.get_ptr()).into_cap());
+        REPLY_SLOTS.lock().insert(caller.get_ptr(), (&**cspace as *const CapNode as usize, slot));
+    } else {
+        mork_kernel_log!(warn, "receiver has no cspace, caller will never be replied");
+    }
+}
+
+pub fn do_reply(current: &mut TaskContext) -> Option<Box<TaskContext>> {
+    reply_with_cptr(current, CNodeSlot::CapReply as usize, CNODE_RADIX_BITS)
+}
+
+/// Replies through the reply cap at `cptr` in `current`'s cspace. The cap is
+/// consumed whether or not the caller is still waiting.
+pub fn reply_with_cptr(current: &mut TaskContext, cptr: usize, depth: usize) -> Option<Box<TaskContext>> {
+    let (cspace, slot) = resolve_address_bits(current.cspace.as_mut()?, cptr, depth).ok()?;
+    if !cspace.is_used(slot) || cspace[slot].get_type() != CapType::Reply {
+        mork_kernel_log!(debug, "no caller to reply");
+        return None;
+    }
+    let caller_ptr = reply_cap_caller(&cspace[slot]);
+    let registered = REPLY_SLOTS.lock().get(&caller_ptr) == Some(&(&*cspace as *const CapNode as usize, slot));
+    mdb::forget_slot(cspace, slot);
+    if !registered {
+        mork_kernel_log!(warn, "stale reply cap to {:#x}", caller_ptr);
+        return None;
+    }
+    REPLY_SLOTS.lock().remove(&caller_ptr);
+    let mut caller = unsafe { Box::from_raw(caller_ptr as *mut TaskContext) };
+    if caller.state != ThreadStateEnum::ThreadStateBlockedOnReply {
+        mork_kernel_log!(warn, "reply target is not waiting for reply: {:?}", caller.state);
+        Box::leak(caller);
+        return None;
+    }
+    caller.blocking_object = None;
+    do_ipc_transfer(current, &mut caller, 0, true);
+    caller.state = ThreadStateEnum::ThreadStateRestart;
+    Some(caller)
+}
+
+/// The slot of the pending reply cap of `current`, if it has one.
+pub fn caller_slot(current: &mut TaskContext) -> Option<(&'static mut CapNode, usize)> {
+    let cspace = current.cspace.as_mut()?;
+    let slot = CNodeSlot::CapReply as usize;
+    if !cspace.is_used(slot) || cspace[slot].get_type() != CapType::Reply {
+        return None;
+    }
+    Some((unsafe { &mut *(&mut **cspace as *mut CapNode) }, slot))
+}
+
+/// Follows the reply cap in `cap` to the slot it is moved to.
+pub fn relocate_reply(cap: &Cap, dest_node: &CapNode, dest_index: usize) {
+    if let Some(slot) = REPLY_SLOTS.lock().get_mut(&reply_cap_caller(cap)) {
+        *slot = (dest_node as *const CapNode as usize, dest_index);
+    }
+}
+
+/// Forgets the reply cap to `caller` once it is deleted.
+pub fn forget_reply(caller: &mut TaskContext) {
+    REPLY_SLOTS.lock().remove(&caller.get_ptr());
+}
+
+/// Drops the reply cap pointing at `caller`, wherever it was moved, so no one
+/// can reply to it after it is gone.
+pub fn cancel_reply(caller: &mut TaskContext) {
+    let Some((cnode, index)) = REPLY_SLOTS.lock().remove(&caller.get_ptr()) else {
+        return;
+    };
+    mdb::forget_slot(unsafe { &mut *(cnode as *mut CapNode) }, index);
+    caller.blocking_object = None;
+}
\ No newline at end of file
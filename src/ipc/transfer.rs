@@ -0,0 +1,72 @@
+use mork_capability::cap::CapType;
+use mork_common::ipc_buffer::MAX_EXTRA_CAPS;
+use mork_common::mork_kernel_log;
+use mork_hal::context::HALContextTrait;
+use mork_task::task::TaskContext;
+use crate::cspace::resolve_cap_arg;
+use crate::mdb;
+
+/// Number of message registers copied from sender to receiver on every IPC.
+pub const IPC_MSG_REG_NUM: usize = 4;
+
+/// Message register holding the OR-ed badge word of a notification receive.
+pub const NOTIFICATION_BADGE_MR: usize = 0;
+
+pub fn deliver_badge(receiver: &mut TaskContext, badge: usize) {
+    receiver.hal_context.set_mr(NOTIFICATION_BADGE_MR, badge);
+}
+
+pub fn do_ipc_transfer(sender: &TaskContext, receiver: &mut TaskContext, badge: usize, can_grant: bool) {
+    for i in 0..IPC_MSG_REG_NUM {
+        receiver.hal_context.set_mr(i, sender.hal_context.get_mr(i));
+    }
+    let mut tag = sender.hal_context.get_tag();
+    let extra_caps = if can_grant && tag.get_extra_caps() > 0 {
+        transfer_caps(sender, receiver, tag.get_extra_caps())
+    } else {
+        0
+    };
+    tag.set_extra_caps(extra_caps);
+    receiver.hal_context.set_tag(tag);
+    receiver.hal_context.set_cap(badge);
+}
+
+/// Derives the caps named in the sender's IPC buffer into the receive slots
+/// declared in the receiver's IPC buffer, both resolved as cap arguments in
+/// their own cspace. Stops at the first cap that can not be transferred and
+/// returns how many were.
+fn transfer_caps(sender: &TaskContext, receiver: &mut TaskContext, count: usize) -> usize {
+    let (Some(src_buffer), Some(src_cspace)) = (sender.get_ipc_buffer(), sender.cspace.as_ref()) else {
+        mork_kernel_log!(warn, "sender has no ipc buffer or cspace");
+        return 0;
+    };
+    let Some(receive_slots) = receiver.get_ipc_buffer().map(|buffer| buffer.receive_slots) else {
+        mork_kernel_log!(warn, "receiver has no ipc buffer");
+        return 0;
+    };
+    let Some(dest_cspace) = receiver.cspace.as_mut() else {
+        mork_kernel_log!(warn, "receiver has no cspace");
+        return 0;
+    };
+    let count = count.min(MAX_EXTRA_CAPS);
+    for i in 0..count {
+        let src_cptr = src_buffer.caps_or_badges[i];
+        let dest_cptr = receive_slots[i];
+        let (Ok((src_node, src_slot)), Ok((dest_node, dest_slot))) =
+            (resolve_cap_arg(src_cspace, src_cptr), resolve_cap_arg(dest_cspace, dest_cptr)) else {
+            mork_kernel_log!(warn, "invalid transfer slot: {:#x} -> {:#x}", src_cptr, dest_cptr);
+            return i;
+        };
+        if !src_node.is_used(src_slot) || dest_node.is_used(dest_slot) {
+            mork_kernel_log!(warn, "can not transfer cap: {:#x} -> {:#x}", src_cptr, dest_cptr);
+            return i;
+        }
+        let src_cap = src_node[src_slot];
+        if matches!(src_cap.get_type(), CapType::Reply | CapType::Untyped) {
+            mork_kernel_log!(warn, "{:?} cap can not be transferred", src_cap.get_type());
+            return i;
+        }
+        mdb::insert_derived(src_node, src_slot, dest_node, dest_slot, src_cap.derive());
+    }
+    count
+}
\ No newline at end of file
@@ -2,8 +2,7 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
-use mork_capability::cap::CapType;
-use mork_common::constants::MAX_CNODE_SIZE;
+use mork_capability::cap::{Cap, CapType};
 use mork_common::mork_kernel_log;
 use mork_common::syscall::message_info::{InvocationLabel, MessageInfo, ResponseLabel};
 use mork_common::syscall::Syscall;
@@ -13,13 +12,22 @@ use mork_task::task_state::ThreadStateEnum;
 
 mod other;
 mod invocation;
+mod ipc;
+mod cspace;
+mod mdb;
+mod quota;
+mod reaper;
 
 pub use invocation::cspace_handler::DeallocHandler;
+pub use invocation::untyped_handler::{create_device_untyped, create_untyped};
 use mork_task::task::TaskContext;
 use mork_ipc::notification::Notification;
+use ipc::endpoint::Endpoint;
+use ipc::transfer::deliver_badge;
+use cspace::{lookup_cap, LookupFault, WORD_BITS};
 
 pub fn handle_syscall(kernel_state: &mut KernelSafeAccessData,
-                      _cptr: usize, _msg_info: MessageInfo, syscall: Syscall) {
+                      cptr: usize, _msg_info: MessageInfo, syscall: Syscall) {
     let mut current = kernel_state.current_task.take().unwrap();
     if current.state == ThreadStateEnum::ThreadStateRunning {
         current.state = ThreadStateEnum::ThreadStateRestart;
@@ -35,18 +43,33 @@ pub fn handle_syscall(kernel_state: &mut KernelSafeAccessData,
         }
         Syscall::Syscall => {
             mork_kernel_log!(debug, "start syscall: {:?}", InvocationLabel::from_usize(_msg_info.get_label()));
-            handle_call(kernel_state, &mut current);
+            handle_call(kernel_state, &mut current, cptr);
+        }
+        Syscall::SysSend => {
+            handle_send(kernel_state, &mut current, cptr, true);
         }
         Syscall::SysNBSend => {
-            handle_nb_send(kernel_state, &mut current);
+            handle_send(kernel_state, &mut current, cptr, false);
         }
         Syscall::SysRecv => {
-            handle_recv(&mut current);
+            handle_recv(kernel_state, &mut current, cptr, true);
+        }
+        Syscall::SysNBRecv => {
+            handle_recv(kernel_state, &mut current, cptr, false);
+        }
+        Syscall::SysReply => {
+            handle_reply(kernel_state, &mut current);
+        }
+        Syscall::SysReplyRecv => {
+            handle_reply_recv(kernel_state, &mut current, cptr);
         }
         _ => {
             panic!("Unsupported syscall type: {:?}", syscall);
         }
     }
+    let Some(mut current) = reaper::reap(kernel_state, current) else {
+        return;
+    };
     if current.state == ThreadStateEnum::ThreadStateRestart {
         kernel_state.scheduler.enqueue_front(current);
     } else {
@@ -55,105 +78,219 @@ pub fn handle_syscall(kernel_state: &mut KernelSafeAccessData,
     }
 }
 
-fn handle_call(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext) {
+fn handle_call(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext, cptr: usize) {
     let mut response = MessageInfo::new_response(ResponseLabel::Success);
-    let dest_cap_idx = current.hal_context.get_cap();
-    if dest_cap_idx >= MAX_CNODE_SIZE {
-        mork_kernel_log!(warn, "invalid dest cap: {}", dest_cap_idx);
-        response = MessageInfo::new_response(ResponseLabel::OutOfRange);
-    } else if let Some(cspace) = current.cspace.as_ref() {
-        let dest_cap = cspace[dest_cap_idx];
-        let message_tag = current.hal_context.get_tag();
-        match dest_cap.get_type() {
-            CapType::Thread => {
-                match invocation::task_handler::handle(
-                    kernel_state,
-                    current,
-                    unsafe { dest_cap.thread_cap },
-                    message_tag
-                ) {
-                    Ok(res) => {
-                        current.hal_context.set_mr(0, res);
-                    }
-                    Err(resp) => {
-                        response = resp;
-                    }
+    let dest_cap = match lookup_dest_cap(current, cptr) {
+        Ok(cap) => cap,
+        Err(resp) => {
+            current.hal_context.set_tag(resp);
+            return;
+        }
+    };
+    let message_tag = current.hal_context.get_tag();
+    match dest_cap.get_type() {
+        CapType::Thread => {
+            match invocation::task_handler::handle(
+                kernel_state,
+                current,
+                unsafe { dest_cap.thread_cap },
+                message_tag
+            ) {
+                Ok(res) => {
+                    current.hal_context.set_mr(0, res);
+                }
+                Err(resp) => {
+                    response = resp;
                 }
             }
+        }
 
-            CapType::PageTable => {
-                match invocation::memory_handler::handle(
-                    current, unsafe { dest_cap.page_table_cap }, message_tag
-                ) {
-                    Ok(_) => {}
-                    Err(resp) => {
-                        response = resp;
-                    }
+        CapType::PageTable => {
+            match invocation::memory_handler::handle(
+                current, unsafe { dest_cap.page_table_cap }, message_tag
+            ) {
+                Ok(_) => {}
+                Err(resp) => {
+                    response = resp;
                 }
             }
-            _ => {
-                mork_kernel_log!(warn, "unSupported cap type: {:?}", dest_cap.get_type());
-                response = MessageInfo::new_response(ResponseLabel::UnSupported);
+        }
+
+        CapType::Frame => {
+            match invocation::memory_handler::handle_frame(current, cptr, message_tag) {
+                Ok(res) => {
+                    current.hal_context.set_mr(0, res);
+                }
+                Err(resp) => {
+                    response = resp;
+                }
             }
         }
-    } else {
-        mork_kernel_log!(warn, "try to find cspace failed");
-        response = MessageInfo::new_response(ResponseLabel::NotEnoughSpace);
+
+        CapType::Untyped => {
+            match invocation::untyped_handler::handle(current, cptr, message_tag) {
+                Ok(res) => {
+                    current.hal_context.set_mr(0, res);
+                }
+                Err(resp) => {
+                    response = resp;
+                }
+            }
+        }
+
+        CapType::Endpoint if !ipc::can_send(&dest_cap) => {
+            mork_kernel_log!(warn, "endpoint cap has no send right");
+            response = MessageInfo::new_response(ResponseLabel::ErrCapType);
+        }
+        CapType::Endpoint => {
+            let endpoint_cap = unsafe { dest_cap.endpoint_cap };
+            let endpoint = Endpoint::from_cap(&endpoint_cap);
+            let badge = endpoint_cap.badge() as usize;
+            let can_grant = endpoint_cap.can_grant() != 0;
+            if let Some(task) = endpoint.send(current, badge, can_grant, true, true) {
+                wake_task(kernel_state, task);
+            }
+            return;
+        }
+        _ => {
+            mork_kernel_log!(warn, "unSupported cap type: {:?}", dest_cap.get_type());
+            response = MessageInfo::new_response(ResponseLabel::UnSupported);
+        }
     }
     current.hal_context.set_tag(response);
 }
 
-fn handle_nb_send(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext) {
+fn handle_send(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext,
+               cptr: usize, blocking: bool) {
     let mut response = MessageInfo::new_response(ResponseLabel::Success);
-    let dest_cap_idx = current.hal_context.get_cap();
-    if dest_cap_idx >= MAX_CNODE_SIZE {
-        mork_kernel_log!(warn, "invalid dest cap: {}", dest_cap_idx);
-        response = MessageInfo::new_response(ResponseLabel::OutOfRange);
-    } else if let Some(cspace) = current.cspace.as_ref() {
-        let dest_cap = cspace[dest_cap_idx];
-        match dest_cap.get_type() {
-            CapType::Notification => {
-                let notification_cap = unsafe { dest_cap.notification_cap };
-                let notification = Notification::from_cap(&notification_cap);
-                let badge = notification_cap.badge() as usize;
-                if let Some(task) = notification.signal(badge) {
-                    kernel_state.scheduler.enqueue_back(task);
-                }
+    let dest_cap = match lookup_dest_cap(current, cptr) {
+        Ok(cap) => cap,
+        Err(resp) => {
+            current.hal_context.set_tag(resp);
+            return;
+        }
+    };
+    match dest_cap.get_type() {
+        _ if !ipc::can_send(&dest_cap) => {
+            mork_kernel_log!(warn, "cap has no send right");
+            response = MessageInfo::new_response(ResponseLabel::ErrCapType);
+        }
+        CapType::Notification => {
+            let notification_cap = unsafe { dest_cap.notification_cap };
+            let notification = Notification::from_cap(&notification_cap);
+            let badge = notification_cap.badge() as usize;
+            if let Some(task) = ipc::notification::signal(notification, badge) {
+                wake_task(kernel_state, task);
             }
-            _ => {
-                mork_kernel_log!(warn, "unSupported cap type: {:?}", dest_cap.get_type());
-                response = MessageInfo::new_response(ResponseLabel::UnSupported);
+        }
+        CapType::Endpoint => {
+            let endpoint_cap = unsafe { dest_cap.endpoint_cap };
+            let endpoint = Endpoint::from_cap(&endpoint_cap);
+            let badge = endpoint_cap.badge() as usize;
+            let can_grant = endpoint_cap.can_grant() != 0;
+            if let Some(task) = endpoint.send(current, badge, can_grant, blocking, false) {
+                wake_task(kernel_state, task);
+            }
+            if current.state == ThreadStateEnum::ThreadStateBlockedOnSend {
+                return;
             }
         }
-    } else {
-        mork_kernel_log!(warn, "try to find cspace failed");
-        response = MessageInfo::new_response(ResponseLabel::NotEnoughSpace);
+        CapType::Reply => {
+            if let Some(caller) = ipc::reply::reply_with_cptr(current, cptr, WORD_BITS) {
+                wake_task(kernel_state, caller);
+            }
+        }
+        _ => {
+            mork_kernel_log!(warn, "unSupported cap type: {:?}", dest_cap.get_type());
+            response = MessageInfo::new_response(ResponseLabel::UnSupported);
+        }
     }
     current.hal_context.set_tag(response);
 }
 
-fn handle_recv(current: &mut TaskContext) {
+/// A notification receive returns `Success` with the accumulated badge word in
+/// MR0, either immediately when a signal is pending or once a signal wakes the
+/// receiver. A non-blocking receive with nothing pending returns `WouldBlock`
+/// and leaves the thread state untouched.
+fn handle_recv(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext,
+               cptr: usize, blocking: bool) {
     let mut response = MessageInfo::new_response(ResponseLabel::Success);
-    let dest_cap_idx = current.hal_context.get_cap();
-    if dest_cap_idx >= MAX_CNODE_SIZE {
-        mork_kernel_log!(warn, "invalid dest cap: {}", dest_cap_idx);
-        response = MessageInfo::new_response(ResponseLabel::OutOfRange);
-    } else if let Some(cspace) = current.cspace.as_ref() {
-        let dest_cap = cspace[dest_cap_idx];
-        match dest_cap.get_type() {
-            CapType::Notification => {
-                let notification_cap = unsafe { dest_cap.notification_cap };
-                let notification = Notification::from_cap(&notification_cap);
-                notification.receive(current);
+    let dest_cap = match lookup_dest_cap(current, cptr) {
+        Ok(cap) => cap,
+        Err(resp) => {
+            current.hal_context.set_tag(resp);
+            return;
+        }
+    };
+    match dest_cap.get_type() {
+        _ if !ipc::can_receive(&dest_cap) => {
+            mork_kernel_log!(warn, "cap has no receive right");
+            response = MessageInfo::new_response(ResponseLabel::ErrCapType);
+        }
+        CapType::Notification => {
+            let notification_cap = unsafe { dest_cap.notification_cap };
+            let notification = Notification::from_cap(&notification_cap);
+            if blocking {
+                if let Some(badge) = ipc::notification::receive(notification, current) {
+                    deliver_badge(current, badge);
+                }
+            } else if let Some(badge) = notification.poll() {
+                deliver_badge(current, badge);
+            } else {
+                response = MessageInfo::new_response(ResponseLabel::WouldBlock);
             }
-            _ => {
-                mork_kernel_log!(warn, "unSupported cap type: {:?}", dest_cap.get_type());
-                response = MessageInfo::new_response(ResponseLabel::UnSupported);
+        }
+        CapType::Endpoint => {
+            let endpoint_cap = unsafe { dest_cap.endpoint_cap };
+            let endpoint = Endpoint::from_cap(&endpoint_cap);
+            if ipc::notification::poll_bound(current) {
+                return;
+            }
+            match endpoint.receive(current, blocking) {
+                Ok(sender) => {
+                    if let Some(task) = sender {
+                        wake_task(kernel_state, task);
+                    }
+                    return;
+                }
+                Err(resp) => {
+                    response = resp;
+                }
             }
         }
-    } else {
-        mork_kernel_log!(warn, "try to find cspace failed");
-        response = MessageInfo::new_response(ResponseLabel::NotEnoughSpace);
+        _ => {
+            mork_kernel_log!(warn, "unSupported cap type: {:?}", dest_cap.get_type());
+            response = MessageInfo::new_response(ResponseLabel::UnSupported);
+        }
     }
     current.hal_context.set_tag(response);
+}
+
+fn handle_reply(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext) {
+    if let Some(caller) = ipc::reply::do_reply(current) {
+        wake_task(kernel_state, caller);
+    }
+}
+
+/// The caller is woken before `current` blocks, so `current` leaves the kernel
+/// either blocked on the receive or, if a message was pending, still restartable.
+fn handle_reply_recv(kernel_state: &mut KernelSafeAccessData, current: &mut TaskContext, cptr: usize) {
+    handle_reply(kernel_state, current);
+    handle_recv(kernel_state, current, cptr, true);
+}
+
+fn lookup_dest_cap(current: &mut TaskContext, cptr: usize) -> Result<Cap, MessageInfo> {
+    let Some(cspace) = current.cspace.as_mut() else {
+        mork_kernel_log!(warn, "try to find cspace failed");
+        return Err(LookupFault::InvalidRoot.report(&mut current.hal_context));
+    };
+    lookup_cap(cspace, cptr).map_err(|fault| {
+        mork_kernel_log!(warn, "lookup dest cap {:#x} failed: {:?}", cptr, fault);
+        fault.report(&mut current.hal_context)
+    })
+}
+
+fn wake_task(kernel_state: &mut KernelSafeAccessData, mut task: Box<TaskContext>) {
+    task.is_queued = true;
+    kernel_state.scheduler.enqueue_back(task);
 }
\ No newline at end of file
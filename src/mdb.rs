@@ -0,0 +1,236 @@
+//! Derivation tree of caps and the refcounts of the objects they refer to.
+//!
+//! The tree, the mappings in `memory_handler` and the reply caps in `reply`
+//! are side tables recording the slot a cap lives in. They stay
+//! coherent only if every write to a CapNode slot in this crate goes through
+//! this module: `insert_root`, `insert_derived`, `update_slot`, `move_slot`,
+//! `delete_slot` or `forget_slot`. Never index a CapNode for writing elsewhere.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use mork_capability::cap::{Cap, CapType};
+use mork_capability::cnode::CapNode;
+use mork_task::task::TaskContext;
+use spin::Mutex;
+use crate::invocation::memory_handler::{relocate_mapping, unmap_slot};
+use crate::ipc::reply::relocate_reply;
+
+/// Derivation tree of every tracked cap, keyed by the address of its slot.
+static MDB: Mutex<MappingDatabase> = Mutex::new(MappingDatabase::new());
+
+/// An object is identified by its cap type and address, since an untyped cap
+/// and the first object retyped from it share the same address.
+type ObjectKey = (usize, usize);
+
+struct MdbNode {
+    cnode: usize,
+    index: usize,
+    object: ObjectKey,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+struct MappingDatabase {
+    nodes: BTreeMap<usize, MdbNode>,
+    /// Number of tracked caps referring to each object.
+    objects: BTreeMap<ObjectKey, usize>,
+    /// Objects whose first cap was created before tracking started. The
+    /// kernel does not know who else holds them, so they are never released.
+    pinned: BTreeSet<ObjectKey>,
+}
+
+impl MappingDatabase {
+    const fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+            objects: BTreeMap::new(),
+            pinned: BTreeSet::new(),
+        }
+    }
+
+    fn insert(&mut self, addr: usize, cnode: usize, index: usize, object: ObjectKey, parent: Option<usize>) {
+        if let Some(parent) = parent.and_then(|parent| self.nodes.get_mut(&parent)) {
+            parent.children.push(addr);
+        }
+        self.nodes.insert(addr, MdbNode { cnode, index, object, parent, children: Vec::new() });
+        *self.objects.entry(object).or_insert(0) += 1;
+    }
+
+    /// Removes the node at `addr`, handing its children over to its parent.
+    /// Returns whether its object may be released because this was the last
+    /// tracked cap to it, or `None` if the slot was not tracked.
+    fn remove(&mut self, addr: usize) -> Option<bool> {
+        let node = self.nodes.remove(&addr)?;
+        if let Some(parent) = node.parent.and_then(|parent| self.nodes.get_mut(&parent)) {
+            parent.children.retain(|child| *child != addr);
+            parent.children.extend_from_slice(&node.children);
+        }
+        for child in node.children.iter() {
+            if let Some(child) = self.nodes.get_mut(child) {
+                child.parent = node.parent;
+            }
+        }
+        let count = self.objects.get_mut(&node.object).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.objects.remove(&node.object);
+            Some(!self.pinned.remove(&node.object))
+        } else {
+            Some(false)
+        }
+    }
+
+    fn has_children(&self, addr: usize) -> bool {
+        self.nodes.get(&addr).is_some_and(|node| !node.children.is_empty())
+    }
+
+    fn is_last(&self, addr: usize) -> bool {
+        self.nodes.get(&addr).is_some_and(|node| {
+            self.objects[&node.object] == 1 && !self.pinned.contains(&node.object)
+        })
+    }
+
+    fn relocate(&mut self, from: usize, to: usize, cnode: usize, index: usize) {
+        let Some(mut node) = self.nodes.remove(&from) else {
+            return;
+        };
+        node.cnode = cnode;
+        node.index = index;
+        if let Some(parent) = node.parent.and_then(|parent| self.nodes.get_mut(&parent)) {
+            for child in parent.children.iter_mut() {
+                if *child == from {
+                    *child = to;
+                }
+            }
+        }
+        for child in node.children.iter() {
+            if let Some(child) = self.nodes.get_mut(child) {
+                child.parent = Some(to);
+            }
+        }
+        self.nodes.insert(to, node);
+    }
+
+    /// The slot of a descendant of `addr` without children of its own.
+    fn leaf_descendant(&self, addr: usize) -> Option<(usize, usize)> {
+        let mut node = self.nodes.get(self.nodes.get(&addr)?.children.last()?)?;
+        while let Some(child) = node.children.last() {
+            node = &self.nodes[child];
+        }
+        Some((node.cnode, node.index))
+    }
+}
+
+pub fn slot_addr(node: &CapNode, index: usize) -> usize {
+    &node[index] as *const Cap as usize
+}
+
+/// The kernel object a cap refers to, or `None` for caps that do not own one.
+pub fn cap_object(cap: &Cap) -> Option<usize> {
+    let object = match cap.get_type() {
+        CapType::Thread => TaskContext::from_cap(unsafe { &cap.thread_cap }).get_ptr(),
+        CapType::Frame => (unsafe { cap.frame_cap }.base_ptr() << 12) as usize,
+        CapType::PageTable => (unsafe { cap.page_table_cap }.base_ptr() << 12) as usize,
+        CapType::Notification => (unsafe { cap.notification_cap }.base_ptr() << 12) as usize,
+        CapType::Endpoint => (unsafe { cap.endpoint_cap }.base_ptr() << 12) as usize,
+        CapType::CNode => (unsafe { cap.cnode_cap }.base_ptr() << 12) as usize,
+        CapType::Untyped => (unsafe { cap.untyped_cap }.base_ptr() << 12) as usize,
+        _ => return None,
+    };
+    Some(object)
+}
+
+fn object_key(cap: &Cap) -> Option<ObjectKey> {
+    cap_object(cap).map(|object| (cap.get_type() as usize, object))
+}
+
+/// Stores a freshly created cap in an empty slot, as the root of a derivation
+/// tree if it refers to an object.
+pub fn insert_root(node: &mut CapNode, index: usize, cap: Cap) {
+    node[index] = cap;
+    if let Some(object) = object_key(&node[index]) {
+        let cnode = node as *mut CapNode as usize;
+        MDB.lock().insert(slot_addr(node, index), cnode, index, object, None);
+    }
+}
+
+/// Stores `cap`, derived from the cap in `src`, in the empty slot `dest`. Caps
+/// created before tracking started become roots on their first derivation,
+/// and their object stays pinned.
+pub fn insert_derived(src_node: &CapNode, src_index: usize, dest_node: &mut CapNode, dest_index: usize, cap: Cap) {
+    dest_node[dest_index] = cap;
+    let Some(object) = object_key(&dest_node[dest_index]) else {
+        return;
+    };
+    let src_addr = slot_addr(src_node, src_index);
+    let mut mdb = MDB.lock();
+    if !mdb.nodes.contains_key(&src_addr) {
+        let src_object = object_key(&src_node[src_index]).unwrap_or(object);
+        mdb.insert(src_addr, src_node as *const CapNode as usize, src_index, src_object, None);
+        mdb.pinned.insert(src_object);
+    }
+    let dest_cnode = dest_node as *mut CapNode as usize;
+    mdb.insert(slot_addr(dest_node, dest_index), dest_cnode, dest_index, object, Some(src_addr));
+}
+
+/// Replaces the cap in a used slot by one to the same object, such as the cap
+/// with an updated mapping, badge or watermark.
+pub fn update_slot(node: &mut CapNode, index: usize, cap: Cap) {
+    node[index] = cap;
+}
+
+/// Moves the cap in `src` to the empty slot `dest`, keeping its place in the
+/// derivation tree.
+pub fn move_slot(src_node: &mut CapNode, src_index: usize, dest_node: &mut CapNode, dest_index: usize) {
+    let (from, to) = (slot_addr(src_node, src_index), slot_addr(dest_node, dest_index));
+    MDB.lock().relocate(from, to, dest_node as *mut CapNode as usize, dest_index);
+    relocate_mapping(src_node, src_index, dest_node, dest_index);
+    if src_node[src_index].get_type() == CapType::Reply {
+        relocate_reply(&src_node[src_index], dest_node, dest_index);
+    }
+    dest_node[dest_index] = src_node.take(src_index);
+}
+
+/// Empties a slot. Any mapping held by the cap is removed first; the object
+/// is only released through its free callback when this was the last tracked
+/// cap referring to it. Untracked caps never release their object.
+pub fn delete_slot(node: &mut CapNode, index: usize) {
+    unmap_slot(node, index);
+    let last = MDB.lock().remove(slot_addr(node, index));
+    if last == Some(true) || cap_object(&node[index]).is_none() {
+        node.free_slot(index);
+    } else {
+        node.take(index);
+    }
+}
+
+/// Empties a slot without releasing what the cap refers to, dropping its place
+/// in the derivation tree. Used for slots of objects being torn down and for
+/// consumed reply caps.
+pub fn forget_slot(node: &mut CapNode, index: usize) {
+    MDB.lock().remove(slot_addr(node, index));
+    node.take(index);
+}
+
+/// Whether any cap is still derived from the cap in `index`.
+pub fn has_children(node: &CapNode, index: usize) -> bool {
+    MDB.lock().has_children(slot_addr(node, index))
+}
+
+/// Whether deleting the cap in `index` would release its object.
+pub fn is_last_cap(node: &CapNode, index: usize) -> bool {
+    MDB.lock().is_last(slot_addr(node, index))
+}
+
+/// Deletes every cap derived from the cap in `index`, in any cspace, leaves
+/// first. Freeing an object may delete further caps, such as those held in a
+/// CNode, so the tree is queried again after every deletion.
+pub fn revoke(node: &mut CapNode, index: usize) {
+    let addr = slot_addr(node, index);
+    loop {
+        let Some((cnode, index)) = MDB.lock().leaf_descendant(addr) else {
+            break;
+        };
+        delete_slot(unsafe { &mut *(cnode as *mut CapNode) }, index);
+    }
+}
@@ -0,0 +1,97 @@
+use alloc::collections::BTreeMap;
+use mork_capability::cnode::CapNode;
+use spin::Mutex;
+
+/// Kernel heap bytes each account may hold through `CNodeAlloc`. The cspaces
+/// of threads created by a cspace draw from the account of their creator.
+pub const CSPACE_MEMORY_QUOTA: usize = 16 * 1024 * 1024;
+
+static QUOTAS: Mutex<Quotas> = Mutex::new(Quotas::new());
+
+struct Quotas {
+    /// Bytes in use, keyed by the address of the charged cspace.
+    used: BTreeMap<usize, usize>,
+    /// The cspace and size charged for each heap object.
+    charges: BTreeMap<usize, (usize, usize)>,
+    /// The account each cspace created by another one is charged to.
+    accounts: BTreeMap<usize, usize>,
+}
+
+impl Quotas {
+    const fn new() -> Self {
+        Self {
+            used: BTreeMap::new(),
+            charges: BTreeMap::new(),
+            accounts: BTreeMap::new(),
+        }
+    }
+
+    fn account(&self, cspace: usize) -> usize {
+        self.accounts.get(&cspace).copied().unwrap_or(cspace)
+    }
+}
+
+fn cspace_addr(cspace: &CapNode) -> usize {
+    cspace as *const CapNode as usize
+}
+
+/// Reserves `size` bytes against the budget of `cspace`. Returns false if
+/// the budget would be exceeded.
+pub fn try_charge(cspace: &CapNode, size: usize) -> bool {
+    let mut quotas = QUOTAS.lock();
+    let account = quotas.account(cspace_addr(cspace));
+    let used = quotas.used.entry(account).or_insert(0);
+    if *used + size > CSPACE_MEMORY_QUOTA {
+        return false;
+    }
+    *used += size;
+    true
+}
+
+/// Returns a reservation made by `try_charge` that was not used.
+pub fn uncharge(cspace: &CapNode, size: usize) {
+    let mut quotas = QUOTAS.lock();
+    let account = quotas.account(cspace_addr(cspace));
+    if let Some(used) = quotas.used.get_mut(&account) {
+        *used -= size;
+    }
+}
+
+/// Records that the object at `object_ptr` holds a charge against `cspace`.
+pub fn record(cspace: &CapNode, object_ptr: usize, size: usize) {
+    let mut quotas = QUOTAS.lock();
+    let account = quotas.account(cspace_addr(cspace));
+    quotas.charges.insert(object_ptr, (account, size));
+}
+
+/// Charges everything later allocated through `cspace` to the account of
+/// `creator`, which made the thread owning it.
+pub fn adopt(cspace: &CapNode, creator: &CapNode) {
+    let mut quotas = QUOTAS.lock();
+    let account = quotas.account(cspace_addr(creator));
+    quotas.accounts.insert(cspace_addr(cspace), account);
+}
+
+/// Credits the size of a freed object back to the cspace it was charged to.
+pub fn credit(object_ptr: usize) {
+    let mut quotas = QUOTAS.lock();
+    if let Some((cspace, size)) = quotas.charges.remove(&object_ptr) {
+        if let Some(used) = quotas.used.get_mut(&cspace) {
+            *used -= size;
+        }
+    }
+}
+
+/// Drops the budget of a cspace that is going away, unless cspaces it created
+/// still draw from it. Objects still charged to it are then freed without
+/// crediting anyone.
+pub fn forget(cspace: &CapNode) {
+    let cspace = cspace_addr(cspace);
+    let mut quotas = QUOTAS.lock();
+    quotas.accounts.remove(&cspace);
+    if quotas.accounts.values().any(|account| *account == cspace) {
+        return;
+    }
+    quotas.used.remove(&cspace);
+    quotas.charges.retain(|_, (charged, _)| *charged != cspace);
+}
\ No newline at end of file
@@ -0,0 +1,63 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use mork_hal::config::PAGE_SIZE_NORMAL;
+use mork_kernel_state::KernelSafeAccessData;
+use mork_task::task::TaskContext;
+use spin::Mutex;
+use crate::invocation::untyped_handler::free_memory;
+use crate::wake_task;
+
+/// Scheduler work left behind by the free callbacks, which run without access
+/// to the kernel state. It is drained before the syscall returns.
+static PENDING: Mutex<Pending> = Mutex::new(Pending::new());
+
+struct Pending {
+    wake: Vec<usize>,
+    dead: Vec<usize>,
+}
+
+impl Pending {
+    const fn new() -> Self {
+        Self {
+            wake: Vec::new(),
+            dead: Vec::new(),
+        }
+    }
+}
+
+/// Queues a task woken by the destruction of the object it was blocked on.
+pub fn defer_wake(task: Box<TaskContext>) {
+    PENDING.lock().wake.push(Box::into_raw(task) as usize);
+}
+
+/// Queues a detached TCB whose memory can be returned once it is off the
+/// scheduler.
+pub fn defer_free_task(task: &mut TaskContext) {
+    PENDING.lock().dead.push(task.get_ptr());
+}
+
+/// Schedules the woken tasks and frees the dead ones. Returns `None` when
+/// `current` itself was destroyed.
+pub fn reap(kernel_state: &mut KernelSafeAccessData, current: Box<TaskContext>) -> Option<Box<TaskContext>> {
+    let (wake, dead) = {
+        let mut pending = PENDING.lock();
+        (core::mem::take(&mut pending.wake), core::mem::take(&mut pending.dead))
+    };
+    for task_ptr in wake {
+        if !dead.contains(&task_ptr) {
+            wake_task(kernel_state, unsafe { Box::from_raw(task_ptr as *mut TaskContext) });
+        }
+    }
+    let mut current = Some(current);
+    for task_ptr in dead {
+        if current.as_ref().is_some_and(|task| task.get_ptr() == task_ptr) {
+            Box::leak(current.take().unwrap());
+        } else if let Some(task) = kernel_state.scheduler.remove(task_ptr) {
+            Box::leak(task);
+        }
+        let layout = Layout::from_size_align(size_of::<TaskContext>(), PAGE_SIZE_NORMAL).unwrap();
+        free_memory(task_ptr, layout);
+    }
+    current
+}
\ No newline at end of file